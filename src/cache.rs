@@ -1,19 +1,43 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::env;
 use serde::{Deserialize, Serialize};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
 use anyhow::{Context, Result};
-use inquire::Select;
-
-use crate::helpers::{copy_to_clipboard_osc52, copy_to_clipboard};
+use inquire::{MultiSelect, Select, Text};
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use regex::Regex;
+use similar::TextDiff;
+
+use crate::helpers::{
+    content_hash, copy_to_clipboard_osc52, copy_to_clipboard, count_tokens, token_counter_id, write_output_file,
+};
 use colored::Colorize;
 use crate::constants::is_cloud_environment;
+use crate::cli::{
+    Args, BlankLines, CacheDiffArgs, CacheDoctorArgs, CacheExportArgs, CacheImportArgs, CacheLsArgs, CacheMergeArgs, CacheNameArgs, CachePruneArgs, CacheRmArgs, CacheSearchArgs, CacheShowArgs, CacheStatsArgs, CacheTagArgs,
+};
+use crate::config::CacheConfig;
+use crate::encryption::{self, Keyring};
+use clap::ValueEnum;
 
-const MAX_CACHE_ENTRIES: usize = 50;
-const MAX_CACHE_SIZE_MB: u64 = 100;
-const MAX_CACHE_AGE_DAYS: i64 = 90;
 const CACHE_DIR_NAME: &str = "xhinobi";
+const OPTIONS_SCHEMA_VERSION: u32 = 1;
+const DEFAULT_GEMINI_MULTIPLIER: f64 = 1.18;
+const PREVIEW_LINES: usize = 20;
+const MAX_PREVIEW_FILES: usize = 50;
+const COMPRESSION_LEVEL: i32 = 3;
+const BLOBS_DIR_NAME: &str = "blobs";
+const LOCK_FILE_NAME: &str = ".lock";
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+const BUNDLE_FORMAT: &str = "xhinobi-bundle";
+const BUNDLE_VERSION: u32 = 1;
+/// Files or directories that mark the root of a project.
+const PROJECT_MARKERS: &[&str] = &[".git", ".xhinobi-project"];
+/// Every zstd frame starts with these bytes; older session files are plain JSON.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheEntry {
@@ -24,8 +48,46 @@ pub struct CacheEntry {
     pub token_counter: Option<String>,
     pub file_size: usize,
     pub source_file_count: usize,
+    /// Options as a flat string, written by versions before `options` existed.
+    #[serde(default, skip_serializing)]
     pub args_used: String,
+    #[serde(default)]
+    pub options: SessionOptions,
     pub working_dir: String,
+    /// Root of the project `working_dir` belongs to; filled in on load for older entries.
+    #[serde(default)]
+    pub project_root: String,
+    /// Source files that went into `content`; empty for entries cached before manifests existed.
+    #[serde(default)]
+    pub files: Vec<ManifestFile>,
+    /// Set on disk when the files' text lives in shared blobs and `content`
+    /// only holds what surrounds it (tree, separators, headers).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub content_in_blobs: bool,
+    #[serde(default)]
+    pub labels: SessionLabels,
+    /// Cached with `--cache-mode metadata`: `content` is empty and the
+    /// manifest's offsets refer to the output as it was produced.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
+}
+
+/// A source file recorded in a cache entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Path relative to the entry's working directory.
+    pub path: String,
+    /// Size on disk in bytes.
+    pub size: u64,
+    /// SHA-256 of the file as read from disk; `None` for files included by name only.
+    pub hash: Option<String>,
+    pub modified: Option<DateTime<Utc>>,
+    /// Byte range of the file's text within the entry's content.
+    pub offset: usize,
+    pub length: usize,
+    /// SHA-256 of the file's text as it appears in the content, naming the blob it is stored in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -42,8 +104,267 @@ pub struct CacheIndexEntry {
     pub token_counter: Option<String>,
     pub file_size: usize,
     pub source_file_count: usize,
+    #[serde(default, skip_serializing)]
     pub args_used: String,
+    #[serde(default)]
+    pub options: SessionOptions,
     pub working_dir: String,
+    #[serde(default)]
+    pub project_root: String,
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Blobs the session refers to, so unreferenced ones can be removed.
+    #[serde(default)]
+    pub blobs: Vec<String>,
+    /// Size of the compressed session file, not counting shared blobs.
+    #[serde(default)]
+    pub disk_size: u64,
+    #[serde(default)]
+    pub labels: SessionLabels,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub metadata_only: bool,
+}
+
+/// A name, tags and pin set on a session by the user.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionLabels {
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Pinned sessions are never removed by automatic cleanup.
+    pub pinned: bool,
+}
+
+impl SessionLabels {
+    fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(name) = &self.name {
+            parts.push(format!("\"{}\"", name));
+        }
+        parts.extend(self.tags.iter().map(|tag| format!("#{}", tag)));
+        if self.pinned {
+            parts.push("pinned".to_string());
+        }
+        parts.join(" ")
+    }
+}
+
+impl CacheEntry {
+    /// Fails for sessions cached without their content.
+    fn require_content(&self) -> Result<()> {
+        if self.metadata_only {
+            return Err(anyhow::anyhow!(
+                "This session was cached without its content (metadata mode); `xhinobi cache rerun` can regenerate it"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Upgrades entries written by older versions to the current format.
+    fn migrate(&mut self) {
+        if self.options.schema_version == 0 {
+            self.options = SessionOptions::from_args_string(&self.args_used);
+        }
+        if self.project_root.is_empty() {
+            self.project_root = project_root_of(&self.working_dir);
+        }
+    }
+}
+
+impl CacheIndex {
+    fn migrate(&mut self) {
+        for entry in &mut self.entries {
+            if entry.options.schema_version == 0 {
+                entry.options = SessionOptions::from_args_string(&entry.args_used);
+            }
+            if entry.project_root.is_empty() {
+                entry.project_root = project_root_of(&entry.working_dir);
+            }
+        }
+    }
+
+    /// Entries of the project `project_root`, or all of them for `None`.
+    fn for_project(self, project_root: Option<&str>) -> Vec<CacheIndexEntry> {
+        self.entries
+            .into_iter()
+            .filter(|e| project_root.is_none_or(|root| e.project_root == root))
+            .collect()
+    }
+}
+
+/// Finds the nearest directory at or above `dir` that contains a `.git` or
+/// `.xhinobi-project` marker. Directories outside any project are their own root.
+pub fn project_root_of(dir: &str) -> String {
+    Path::new(dir)
+        .ancestors()
+        .find(|ancestor| PROJECT_MARKERS.iter().any(|marker| ancestor.join(marker).exists()))
+        .map_or_else(|| dir.to_string(), |root| root.to_string_lossy().to_string())
+}
+
+/// The project root of the current directory.
+pub fn current_project_root() -> String {
+    let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    project_root_of(&current_dir.to_string_lossy())
+}
+
+/// Shortens a path under the home directory to start with `~`.
+fn display_path(path: &str) -> String {
+    match env::var("HOME").ok().and_then(|home| path.strip_prefix(&home).map(str::to_string)) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+        _ => path.to_string(),
+    }
+}
+
+/// The options a session was created with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionOptions {
+    /// Zero for options migrated from an entry that predates this struct.
+    pub schema_version: u32,
+    pub tree: bool,
+    pub decomment: bool,
+    pub decomment_fences: bool,
+    pub blank_lines: Option<BlankLines>,
+    pub minify: bool,
+    pub prepend_file_name: bool,
+    pub osc52: bool,
+    pub ignore: Vec<String>,
+    pub token_counter: String,
+    pub gemini_multiplier: f64,
+    pub output_file: Option<String>,
+}
+
+impl SessionOptions {
+    pub fn from_args(args: &Args) -> Self {
+        SessionOptions {
+            schema_version: OPTIONS_SCHEMA_VERSION,
+            tree: args.tree,
+            decomment: args.decomment,
+            decomment_fences: args.decomment_fences,
+            blank_lines: args.blank_lines,
+            minify: args.minify,
+            prepend_file_name: args.prepend_file_name,
+            osc52: args.osc52,
+            ignore: args.ignore.clone(),
+            token_counter: token_counter_id(&args.token_counter).to_string(),
+            gemini_multiplier: args.gemini_multiplier,
+            output_file: args.output_file.clone(),
+        }
+    }
+
+    /// Parses the `key=value` string older versions stored in `args_used`.
+    fn from_args_string(args_used: &str) -> Self {
+        const KEYS: &[&str] = &[
+            "tree", "decomment", "minify", "prepend", "osc52", "ignore", "token_counter", "gemini_multiplier", "output_file",
+        ];
+
+        // Values may contain spaces (ignore patterns), so a word without a known key continues the previous value
+        let mut values: Vec<(&str, String)> = Vec::new();
+        for word in args_used.split(' ') {
+            match word.split_once('=') {
+                Some((key, value)) if KEYS.contains(&key) => values.push((key, value.to_string())),
+                _ => {
+                    if let Some((_, value)) = values.last_mut() {
+                        value.push(' ');
+                        value.push_str(word);
+                    }
+                }
+            }
+        }
+
+        let mut options = SessionOptions {
+            token_counter: "estimate".to_string(),
+            gemini_multiplier: DEFAULT_GEMINI_MULTIPLIER,
+            ..SessionOptions::default()
+        };
+        for (key, value) in values {
+            match key {
+                "tree" => options.tree = value == "true",
+                "decomment" => options.decomment = value == "true",
+                "minify" => options.minify = value == "true",
+                "prepend" => options.prepend_file_name = value == "true",
+                "osc52" => options.osc52 = value == "true",
+                "ignore" => {
+                    options.ignore = value.split(',').filter(|p| !p.is_empty()).map(String::from).collect();
+                }
+                "token_counter" => options.token_counter = value,
+                "gemini_multiplier" => {
+                    options.gemini_multiplier = value.parse().unwrap_or(DEFAULT_GEMINI_MULTIPLIER);
+                }
+                "output_file" if value != "none" => options.output_file = Some(value),
+                _ => {}
+            }
+        }
+
+        options
+    }
+
+    /// Turns the options back into command-line flags that reproduce the session's output.
+    /// Clipboard and output file settings are left to the caller.
+    pub fn to_flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        for (enabled, flag) in [
+            (self.tree, "--tree"),
+            (self.decomment, "--decomment"),
+            (self.decomment_fences, "--decomment-fences"),
+            (self.minify, "--minify"),
+            (self.prepend_file_name, "--prependFileName"),
+        ] {
+            if enabled {
+                flags.push(flag.to_string());
+            }
+        }
+        if let Some(blank_lines) = self.blank_lines {
+            if let Some(value) = blank_lines.to_possible_value() {
+                flags.push("--blank-lines".to_string());
+                flags.push(value.get_name().to_string());
+            }
+        }
+        for pattern in &self.ignore {
+            flags.push("--ignore".to_string());
+            flags.push(pattern.clone());
+        }
+        if !self.token_counter.is_empty() {
+            flags.push("--token-counter".to_string());
+            flags.push(self.token_counter.clone());
+        }
+        flags.push("--gemini-multiplier".to_string());
+        flags.push(self.gemini_multiplier.to_string());
+
+        flags
+    }
+
+    /// A short human-readable description of the options that were enabled.
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = [
+            (self.tree, "tree"),
+            (self.decomment, "decomment"),
+            (self.decomment_fences, "decomment-fences"),
+            (self.minify, "minify"),
+            (self.prepend_file_name, "prepend"),
+            (self.osc52, "osc52"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+        if let Some(value) = self.blank_lines.and_then(|b| b.to_possible_value()) {
+            parts.push(format!("blank-lines={}", value.get_name()));
+        }
+        if !self.ignore.is_empty() {
+            parts.push(format!("ignore=[{}]", self.ignore.join(", ")));
+        }
+        parts.push(format!("token-counter={}", self.token_counter));
+        if self.token_counter == "gemini-approx" {
+            parts.push(format!("gemini-multiplier={}", self.gemini_multiplier));
+        }
+        if let Some(output_file) = &self.output_file {
+            parts.push(format!("output-file={}", output_file));
+        }
+
+        parts.join(" ")
+    }
 }
 
 fn token_prefix(entry: &CacheEntry) -> &'static str {
@@ -56,40 +377,72 @@ fn token_prefix(entry: &CacheEntry) -> &'static str {
 }
 
 pub fn get_cache_dir(override_dir: &Option<String>) -> Result<PathBuf> {
-    let cache_dir = if let Some(custom_dir) = override_dir {
-        PathBuf::from(custom_dir)
+    let (cache_dir, own_dir) = if let Some(custom_dir) = override_dir {
+        (PathBuf::from(custom_dir), false)
     } else {
         // Use XDG cache directory or fallback to ~/.cache
         let xdg_cache_home = env::var("XDG_CACHE_HOME").ok();
-        if let Some(xdg_dir) = xdg_cache_home {
+        let cache_dir = if let Some(xdg_dir) = xdg_cache_home {
             PathBuf::from(xdg_dir).join(CACHE_DIR_NAME)
         } else {
             let home = env::var("HOME").context("Could not find HOME directory")?;
             PathBuf::from(home).join(".cache").join(CACHE_DIR_NAME)
-        }
+        };
+        (cache_dir, true)
     };
 
     let sessions_dir = cache_dir.join("sessions");
-    fs::create_dir_all(&sessions_dir).context("Failed to create cache directory")?;
+    create_private_dir(&sessions_dir).context("Failed to create cache directory")?;
+    restrict_cache_permissions(&cache_dir, own_dir).context("Failed to restrict cache permissions")?;
 
     Ok(cache_dir)
 }
 
+/// Takes group and other access away from a cache created before its files
+/// were made private. A private `sessions/` means this was already done.
+/// A directory given with `--cache-dir` may be shared, so only what xhinobi
+/// created inside it is changed (`own_dir` is false).
+fn restrict_cache_permissions(cache_dir: &Path, own_dir: bool) -> std::io::Result<()> {
+    if own_dir {
+        restrict_permissions(cache_dir)?;
+    }
+    if !restrict_permissions(&cache_dir.join("sessions"))? {
+        return Ok(());
+    }
+    let dirs = [cache_dir.join("sessions"), blobs_dir(cache_dir), cache_dir.join(QUARANTINE_DIR_NAME)];
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        restrict_permissions(dir)?;
+        for entry in fs::read_dir(dir)?.flatten() {
+            restrict_permissions(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes group and other permissions from a file or directory, leaving
+/// symlinks alone. Returns whether anything changed.
+fn restrict_permissions(path: &Path) -> std::io::Result<bool> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mode = metadata.permissions().mode();
+    if metadata.file_type().is_symlink() || mode & 0o077 == 0 {
+        return Ok(false);
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & !0o077))?;
+    Ok(true)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn save_to_cache(
     content: &str,
-    source_file_count: usize,
-    args_used: &str,
+    files: Vec<ManifestFile>,
+    options: SessionOptions,
     cache_dir_override: &Option<String>,
     token_count: usize,
-    token_counter: Option<String>,
+    metadata_only: bool,
+    retention: &CacheConfig,
+    keyring: &Keyring,
 ) -> Result<()> {
     let cache_dir = get_cache_dir(cache_dir_override)?;
-    let sessions_dir = cache_dir.join("sessions");
-
-    // Create timestamped filename
-    let timestamp = Utc::now();
-    let filename = format!("{}.cache", timestamp.format("%Y-%m-%d_%H-%M-%S"));
-    let file_path = sessions_dir.join(&filename);
 
     // Create cache entry
     let working_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
@@ -97,81 +450,288 @@ pub fn save_to_cache(
         .to_string();
 
     let entry = CacheEntry {
-        timestamp,
-        content: content.to_string(),
+        timestamp: Utc::now(),
+        content: if metadata_only { String::new() } else { content.to_string() },
         token_count,
-        token_counter,
+        token_counter: Some(options.token_counter.clone()),
         file_size: content.len(),
-        source_file_count,
-        args_used: args_used.to_string(),
+        source_file_count: files.len(),
+        args_used: String::new(),
+        options,
+        project_root: project_root_of(&working_dir),
         working_dir,
+        files,
+        content_in_blobs: false,
+        labels: SessionLabels::default(),
+        metadata_only,
     };
 
-    // Save cache entry
-    let serialized = serde_json::to_string(&entry).context("Failed to serialize cache entry")?;
-    fs::write(&file_path, serialized).context("Failed to write cache file")?;
+    add_session(&cache_dir, &entry, retention, keyring)?;
+
+    if metadata_only {
+        println!("Cached metadata for {} files (content not stored)", entry.source_file_count);
+    } else {
+        println!(
+            "Cached result ({} characters, {}{} tokens)",
+            content.len(),
+            token_prefix(&entry),
+            entry.token_count.to_string().cyan()
+        );
+    }
+
+    Ok(())
+}
+
+/// Stores a new session under a fresh file name, indexes it and applies the
+/// retention limits. Returns the session's file name.
+fn add_session(cache_dir: &Path, entry: &CacheEntry, retention: &CacheConfig, keyring: &Keyring) -> Result<String> {
+    let sessions_dir = cache_dir.join("sessions");
+    let _lock = lock_cache(cache_dir)?;
 
-    // Update index
-    update_cache_index(&cache_dir, &entry, &filename)?;
+    let filename = new_session_filename(&sessions_dir, entry.timestamp);
+    let stored = write_session_file(cache_dir, &sessions_dir.join(&filename), entry, keyring)?;
+    update_cache_index(cache_dir, &stored, &filename, keyring)?;
 
     // Cleanup old entries if needed
-    cleanup_cache(&cache_dir)?;
-
-    println!(
-        "Cached result ({} characters, {}{} tokens)",
-        content.len(),
-        token_prefix(&entry),
-        entry.token_count.to_string().cyan()
-    );
+    cleanup_cache(cache_dir, retention, keyring)?;
 
-    Ok(())
+    Ok(filename)
 }
 
-pub fn load_most_recent_cache(cache_dir_override: &Option<String>) -> Result<CacheEntry> {
-    let cache_dir = get_cache_dir(cache_dir_override)?;
-    let index_path = cache_dir.join("sessions").join("cache_index.json");
+/// Takes an exclusive lock on the cache, held until the returned file is dropped.
+///
+/// Every read-modify-write of the index or the session files must hold it so
+/// that parallel runs don't lose each other's changes.
+fn lock_cache(cache_dir: &Path) -> Result<fs::File> {
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .mode(0o600)
+        .open(cache_dir.join("sessions").join(LOCK_FILE_NAME))
+        .context("Failed to open cache lock file")?;
+    lock_file.lock().context("Failed to lock cache")?;
+    Ok(lock_file)
+}
 
-    if !index_path.exists() {
-        return Err(anyhow::anyhow!("No cache found"));
+/// Names a session after its creation time, with the process ID and, if
+/// needed, a counter keeping names from parallel runs apart.
+fn new_session_filename(sessions_dir: &Path, timestamp: DateTime<Utc>) -> String {
+    let base = format!("{}_{}", timestamp.format("%Y-%m-%d_%H-%M-%S-%3f"), std::process::id());
+    let mut filename = format!("{}.cache", base);
+    let mut counter = 1;
+    while sessions_dir.join(&filename).exists() {
+        counter += 1;
+        filename = format!("{}-{}.cache", base, counter);
     }
+    filename
+}
 
-    let index_content = fs::read_to_string(&index_path).context("Failed to read cache index")?;
-    let index: CacheIndex = serde_json::from_str(&index_content).context("Failed to parse cache index")?;
+/// Writes to a temporary file next to `path` and renames it into place, so
+/// readers see either the old or the new content, never a partial write.
+/// Like `write_output_file`, the file is only readable by its owner.
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("cache");
+    let temp_path = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&temp_path)?;
+    // `mode` only applies to new files; a leftover temporary file keeps its own
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    fs::rename(&temp_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp_path);
+    })
+}
+
+/// Creates a directory (and its parents) that only its owner can enter.
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(path)
+}
 
-    if index.entries.is_empty() {
-        return Err(anyhow::anyhow!("No cache entries found"));
+/// Compresses data for a cache file and encrypts it when encryption is on.
+fn seal(cache_dir: &Path, data: &[u8], keyring: &Keyring) -> Result<Vec<u8>> {
+    let compressed = zstd::encode_all(data, COMPRESSION_LEVEL).context("Failed to compress cache data")?;
+    if keyring.enabled() {
+        keyring.encrypt(&cache_dir.join("sessions"), &compressed)
+    } else {
+        Ok(compressed)
+    }
+}
+
+/// Reverses `seal`, also accepting files written without encryption or
+/// before compression was introduced.
+fn unseal(bytes: Vec<u8>, keyring: &Keyring) -> Result<Vec<u8>> {
+    let bytes = if encryption::is_encrypted(&bytes) {
+        keyring.decrypt(&bytes)?
+    } else {
+        bytes
+    };
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        zstd::decode_all(&bytes[..]).context("Failed to decompress cache data")
+    } else {
+        Ok(bytes)
     }
+}
+
+/// Loads the newest session of the project `project_root`, or of any project for `None`.
+pub fn load_most_recent_cache(
+    cache_dir_override: &Option<String>,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<CacheEntry> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = read_cache_index(&cache_dir, keyring)?.for_project(project_root);
 
     // Find most recent entry
-    let most_recent = index.entries.iter()
-        .max_by_key(|e| e.timestamp)
-        .ok_or_else(|| anyhow::anyhow!("No cache entries found"))?;
+    let most_recent = entries.iter().max_by_key(|e| e.timestamp).ok_or_else(|| match project_root {
+        Some(root) => anyhow::anyhow!(
+            "No cache entries found for {} (use --all-projects for other projects)",
+            display_path(root)
+        ),
+        None => anyhow::anyhow!("No cache entries found"),
+    })?;
+
+    load_cache_entry(&cache_dir, &most_recent.filename, keyring)
+}
 
-    let cache_file_path = cache_dir.join("sessions").join(&most_recent.filename);
-    let cache_content = fs::read_to_string(&cache_file_path).context("Failed to read cache file")?;
-    let entry: CacheEntry = serde_json::from_str(&cache_content).context("Failed to parse cache entry")?;
+fn load_cache_entry(cache_dir: &Path, filename: &str, keyring: &Keyring) -> Result<CacheEntry> {
+    let mut entry = read_stored_entry(&cache_dir.join("sessions").join(filename), keyring)?;
+    if entry.content_in_blobs {
+        entry.content = assemble_content(cache_dir, &entry.content, &entry.files, keyring)?;
+        entry.content_in_blobs = false;
+    }
+    entry.migrate();
 
     Ok(entry)
 }
 
-pub fn list_cache_entries(cache_dir_override: &Option<String>) -> Result<Vec<CacheIndexEntry>> {
-    let cache_dir = get_cache_dir(cache_dir_override)?;
-    let index_path = cache_dir.join("sessions").join("cache_index.json");
+/// Reads a session file as stored, without putting its blobs back into the content.
+fn read_stored_entry(path: &Path, keyring: &Keyring) -> Result<CacheEntry> {
+    let bytes = fs::read(path).context("Failed to read cache file")?;
+    let json = unseal(bytes, keyring)?;
+    serde_json::from_slice(&json).context("Failed to parse cache entry")
+}
 
-    if !index_path.exists() {
-        return Ok(vec![]);
+fn write_stored_entry(cache_dir: &Path, path: &Path, entry: &CacheEntry, keyring: &Keyring) -> Result<()> {
+    let serialized = serde_json::to_vec(entry).context("Failed to serialize cache entry")?;
+    let sealed = seal(cache_dir, &serialized, keyring)?;
+    write_atomic(path, &sealed).context("Failed to write cache file")
+}
+
+fn blobs_dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("sessions").join(BLOBS_DIR_NAME)
+}
+
+/// Writes a session as compressed JSON, moving each file's text into a blob
+/// shared with every other session that contains the same text.
+///
+/// Returns the entry as stored, with blob references filled in.
+fn write_session_file(
+    cache_dir: &Path,
+    file_path: &Path,
+    entry: &CacheEntry,
+    keyring: &Keyring,
+) -> Result<CacheEntry> {
+    let blobs_dir = blobs_dir(cache_dir);
+    create_private_dir(&blobs_dir).context("Failed to create blob directory")?;
+
+    let mut stored = entry.clone();
+    stored.content = String::with_capacity(entry.content.len());
+    stored.content_in_blobs = true;
+
+    let mut files: Vec<&mut ManifestFile> = stored.files.iter_mut().collect();
+    files.sort_by_key(|f| f.offset);
+    let mut position = 0;
+    for file in files {
+        let text = entry.content.get(file.offset..file.offset + file.length);
+        let Some(text) = text.filter(|t| !t.is_empty() && file.offset >= position) else {
+            continue;
+        };
+
+        let hash = if keyring.enabled() {
+            keyring.keyed_hash(&cache_dir.join("sessions"), text.as_bytes())?
+        } else {
+            content_hash(text.as_bytes())
+        };
+        let blob_path = blobs_dir.join(format!("{}.zst", hash));
+        if !blob_path.exists() {
+            let sealed = seal(cache_dir, text.as_bytes(), keyring)?;
+            write_atomic(&blob_path, &sealed).context("Failed to write cache blob")?;
+        }
+
+        stored.content.push_str(&entry.content[position..file.offset]);
+        position = file.offset + file.length;
+        file.blob = Some(hash);
     }
+    stored.content.push_str(&entry.content[position..]);
+    write_stored_entry(cache_dir, file_path, &stored, keyring)?;
 
-    let index_content = fs::read_to_string(&index_path).context("Failed to read cache index")?;
-    let mut index: CacheIndex = serde_json::from_str(&index_content).context("Failed to parse cache index")?;
+    Ok(stored)
+}
+
+/// Rebuilds a session's content by putting the files' blobs back into the
+/// text that surrounded them.
+fn assemble_content(cache_dir: &Path, frame: &str, files: &[ManifestFile], keyring: &Keyring) -> Result<String> {
+    let blobs_dir = blobs_dir(cache_dir);
+    let mut stored: Vec<&ManifestFile> = files.iter().filter(|f| f.blob.is_some()).collect();
+    stored.sort_by_key(|f| f.offset);
+
+    let mut content = String::with_capacity(frame.len() + stored.iter().map(|f| f.length).sum::<usize>());
+    let mut position = 0;
+    for file in stored {
+        let hash = file.blob.as_deref().unwrap_or_default();
+        let take = file.offset.checked_sub(content.len())
+            .filter(|take| position + take <= frame.len())
+            .context("Cache entry manifest does not match its content")?;
+        content.push_str(&frame[position..position + take]);
+        position += take;
+
+        let sealed = fs::read(blobs_dir.join(format!("{}.zst", hash)))
+            .with_context(|| format!("Missing cache blob for {}", file.path))?;
+        let text = unseal(sealed, keyring)?;
+        content.push_str(&String::from_utf8(text).context("Cache blob is not valid UTF-8")?);
+    }
+    content.push_str(&frame[position..]);
+
+    Ok(content)
+}
+
+/// Deletes blobs that no session in the index refers to.
+fn remove_unreferenced_blobs(cache_dir: &Path, index: &CacheIndex) {
+    let referenced: HashSet<&str> = index.entries.iter()
+        .flat_map(|e| e.blobs.iter().map(String::as_str))
+        .collect();
+
+    if let Ok(entries) = fs::read_dir(blobs_dir(cache_dir)) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(hash) = name.to_str().and_then(|n| n.strip_suffix(".zst")) else {
+                continue;
+            };
+            if !referenced.contains(hash) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+pub fn list_cache_entries(cache_dir_override: &Option<String>, keyring: &Keyring) -> Result<Vec<CacheIndexEntry>> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let mut index = read_cache_index(&cache_dir, keyring)?;
 
     // Sort entries by timestamp descending (newest first)
     index.entries.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
 
-    Ok(index.entries)
+    Ok(group_by_project(index.entries))
 }
 
 pub fn copy_cache_to_clipboard(entry: &CacheEntry, osc52: bool) -> Result<()> {
+    entry.require_content()?;
     if osc52 {
         copy_to_clipboard_osc52(&entry.content);
         println!(
@@ -211,147 +771,379 @@ pub fn copy_cache_to_clipboard(entry: &CacheEntry, osc52: bool) -> Result<()> {
     Ok(())
 }
 
+/// Writes a session's content to stdout exactly as it was cached.
+pub fn print_cache_content(entry: &CacheEntry) -> Result<()> {
+    entry.require_content()?;
+    let mut stdout = std::io::stdout().lock();
+    ignore_broken_pipe(stdout.write_all(entry.content.as_bytes()).and_then(|_| stdout.flush()))
+        .context("Failed to write to stdout")
+}
+
 pub fn clear_cache(cache_dir_override: &Option<String>) -> Result<()> {
     let cache_dir = get_cache_dir(cache_dir_override)?;
     let sessions_dir = cache_dir.join("sessions");
+    let _lock = lock_cache(&cache_dir)?;
 
-    if sessions_dir.exists() {
-        fs::remove_dir_all(&sessions_dir).context("Failed to remove cache directory")?;
-        fs::create_dir(&sessions_dir).context("Failed to recreate cache directory")?;
-        println!("Cache cleared successfully");
-    } else {
-        println!("No cache directory found");
+    // Keep the lock file so that runs waiting on it still exclude each other
+    for entry in fs::read_dir(&sessions_dir).context("Failed to read cache directory")?.flatten() {
+        if entry.file_name() == LOCK_FILE_NAME {
+            continue;
+        }
+        let path = entry.path();
+        if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .context("Failed to remove cache files")?;
     }
+    println!("Cache cleared successfully");
 
     Ok(())
 }
 
-fn update_cache_index(cache_dir: &Path, entry: &CacheEntry, filename: &str) -> Result<()> {
-    let index_path = cache_dir.join("sessions").join("cache_index.json");
+fn update_cache_index(cache_dir: &Path, entry: &CacheEntry, filename: &str, keyring: &Keyring) -> Result<()> {
+    let mut index = read_cache_index(cache_dir, keyring)?;
 
-    let mut index = if index_path.exists() {
-        let index_content = fs::read_to_string(&index_path).context("Failed to read existing index")?;
-        serde_json::from_str(&index_content).context("Failed to parse existing index")?
-    } else {
-        CacheIndex { entries: vec![] }
-    };
+    // A rebuilt index may already have picked up the new session file
+    index.entries.retain(|e| e.filename != filename);
+    index.entries.push(index_entry_for(cache_dir, entry, filename));
+
+    write_cache_index(cache_dir, &index)
+}
 
-    let index_entry = CacheIndexEntry {
+/// Builds the index entry for a session as stored on disk.
+fn index_entry_for(cache_dir: &Path, entry: &CacheEntry, filename: &str) -> CacheIndexEntry {
+    CacheIndexEntry {
         filename: filename.to_string(),
         timestamp: entry.timestamp,
         token_count: entry.token_count,
         token_counter: entry.token_counter.clone(),
         file_size: entry.file_size,
         source_file_count: entry.source_file_count,
-        args_used: entry.args_used.clone(),
+        args_used: String::new(),
+        options: entry.options.clone(),
         working_dir: entry.working_dir.clone(),
+        project_root: entry.project_root.clone(),
+        files: entry.files.iter().map(|f| f.path.clone()).collect(),
+        blobs: entry.files.iter().filter_map(|f| f.blob.clone()).collect(),
+        disk_size: fs::metadata(cache_dir.join("sessions").join(filename)).map_or(0, |m| m.len()),
+        labels: entry.labels.clone(),
+        metadata_only: entry.metadata_only,
+    }
+}
+
+/// A session the retention policy removes, and why.
+pub struct Eviction {
+    pub filename: String,
+    pub reason: String,
+}
+
+/// The sessions a cleanup removes and the disk usage before and after.
+pub struct CleanupPlan {
+    pub evictions: Vec<Eviction>,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// Works out which sessions fall outside the retention policy, oldest first
+/// within each rule: age, per-project count, global count, then disk size.
+/// Pinned sessions are left alone and do not count towards the limits.
+fn plan_cleanup(cache_dir: &Path, index: &CacheIndex, policy: &CacheConfig) -> CleanupPlan {
+    let sessions_dir = cache_dir.join("sessions");
+    let now = Utc::now();
+
+    // Newest first, so each count limit keeps the most recent sessions
+    let (pinned, mut kept): (Vec<&CacheIndexEntry>, Vec<&CacheIndexEntry>) =
+        index.entries.iter().partition(|e| e.labels.pinned);
+    kept.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+    let mut evictions = Vec::new();
+
+    kept.retain(|e| {
+        let max_age_days = policy
+            .project_for(&e.working_dir)
+            .and_then(|(_, rules)| rules.max_age_days)
+            .unwrap_or(policy.max_age_days);
+        let cutoff = chrono::TimeDelta::try_days(max_age_days).and_then(|age| now.checked_sub_signed(age));
+        if cutoff.is_none_or(|cutoff| e.timestamp > cutoff) {
+            return true;
+        }
+        evictions.push(Eviction {
+            filename: e.filename.clone(),
+            reason: format!("older than {} days", max_age_days),
+        });
+        false
+    });
+
+    let mut project_counts: HashMap<&str, usize> = HashMap::new();
+    kept.retain(|e| {
+        let Some((project, rules)) = policy.project_for(&e.working_dir) else {
+            return true;
+        };
+        let Some(max_entries) = rules.max_entries else {
+            return true;
+        };
+        let count = project_counts.entry(project).or_default();
+        *count += 1;
+        if *count <= max_entries {
+            return true;
+        }
+        evictions.push(Eviction {
+            filename: e.filename.clone(),
+            reason: format!("over the limit of {} sessions for {}", max_entries, project),
+        });
+        false
+    });
+
+    if kept.len() > policy.max_entries {
+        for e in kept.drain(policy.max_entries..) {
+            evictions.push(Eviction {
+                filename: e.filename.clone(),
+                reason: format!("over the limit of {} sessions", policy.max_entries),
+            });
+        }
+    }
+
+    // Sessions share blobs, so a blob only stops counting once its last session is gone
+    let session_size = |e: &CacheIndexEntry| fs::metadata(sessions_dir.join(&e.filename)).map_or(0, |m| m.len());
+    let blob_sizes = blob_sizes(cache_dir);
+    let usage = |entries: &[&CacheIndexEntry]| -> u64 {
+        let blobs: HashSet<&str> = entries.iter().flat_map(|e| e.blobs.iter().map(String::as_str)).collect();
+        entries.iter().map(|e| session_size(e)).sum::<u64>()
+            + blobs.iter().filter_map(|hash| blob_sizes.get(*hash)).sum::<u64>()
     };
 
-    index.entries.push(index_entry);
+    let all: Vec<&CacheIndexEntry> = index.entries.iter().collect();
+    let retained: Vec<&CacheIndexEntry> = kept.iter().chain(&pinned).copied().collect();
+    let size_before = usage(&all);
+    let mut size_after = usage(&retained);
 
-    let serialized = serde_json::to_string(&index).context("Failed to serialize updated index")?;
-    fs::write(&index_path, serialized).context("Failed to write updated index")?;
+    let max_size_bytes = policy.max_size_mb.saturating_mul(1024 * 1024);
+    if size_after > max_size_bytes {
+        let mut references: HashMap<&str, usize> = HashMap::new();
+        for hash in retained.iter().flat_map(|e| &e.blobs) {
+            *references.entry(hash).or_default() += 1;
+        }
+        while size_after > max_size_bytes {
+            let Some(oldest) = kept.pop() else {
+                break;
+            };
+            size_after -= session_size(oldest);
+            for hash in &oldest.blobs {
+                let count = references.entry(hash).or_default();
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    size_after -= blob_sizes.get(hash).copied().unwrap_or(0);
+                }
+            }
+            evictions.push(Eviction {
+                filename: oldest.filename.clone(),
+                reason: format!("cache over {} MB", policy.max_size_mb),
+            });
+        }
+    }
 
-    Ok(())
+    CleanupPlan {
+        evictions,
+        size_before,
+        size_after,
+    }
 }
 
-fn cleanup_cache(cache_dir: &Path) -> Result<()> {
+fn cleanup_cache(cache_dir: &Path, policy: &CacheConfig, keyring: &Keyring) -> Result<CleanupPlan> {
     let sessions_dir = cache_dir.join("sessions");
-    let index_path = sessions_dir.join("cache_index.json");
+    let mut index = read_cache_index(cache_dir, keyring)?;
+
+    // Pick up sessions missing from the index instead of losing them
+    let mut keep_blobs = false;
+    for problem in reconcile_index(cache_dir, &mut index, false, keyring) {
+        match &problem {
+            CacheProblem::UnreadableSession(filename, _) => {
+                let destination = quarantine_session(cache_dir, filename)?;
+                eprintln!("Warning: Moved unreadable cache file {} to {}", filename, destination.display());
+            }
+            CacheProblem::UndecryptableSession(filename, error) => {
+                eprintln!("Warning: Skipping {}: {}", filename, error);
+                keep_blobs = true;
+            }
+            _ => {}
+        }
+    }
 
-    if !index_path.exists() {
-        return Ok(());
+    let plan = plan_cleanup(cache_dir, &index, policy);
+
+    let evicted: HashSet<&str> = plan.evictions.iter().map(|e| e.filename.as_str()).collect();
+    index.entries.retain(|e| !evicted.contains(e.filename.as_str()));
+    for filename in evicted {
+        let _ = fs::remove_file(sessions_dir.join(filename));
+    }
+    // The blobs of a session that can't be decrypted look unreferenced
+    if !keep_blobs {
+        remove_unreferenced_blobs(cache_dir, &index);
     }
 
-    let index_content = fs::read_to_string(&index_path).context("Failed to read index for cleanup")?;
-    let mut index: CacheIndex = serde_json::from_str(&index_content).context("Failed to parse index for cleanup")?;
+    // Write updated index
+    write_cache_index(cache_dir, &index)?;
+    Ok(plan)
+}
 
-    // Remove entries older than MAX_CACHE_AGE_DAYS
-    let cutoff_date = Utc::now() - chrono::Duration::days(MAX_CACHE_AGE_DAYS);
-    index.entries.retain(|e| e.timestamp > cutoff_date);
+/// Applies the retention policy now, or only reports what it would remove.
+pub fn prune_cache(
+    cache_dir_override: &Option<String>,
+    policy: &CacheConfig,
+    args: &CachePruneArgs,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let plan = if args.dry_run {
+        plan_cleanup(&cache_dir, &read_cache_index(&cache_dir, keyring)?, policy)
+    } else {
+        let _lock = lock_cache(&cache_dir)?;
+        cleanup_cache(&cache_dir, policy, keyring)?
+    };
 
-    // Limit by number of entries
-    if index.entries.len() > MAX_CACHE_ENTRIES {
-        index.entries.sort_by_key(|e| e.timestamp);
-        index.entries.truncate(MAX_CACHE_ENTRIES);
-        index.entries.sort_by_key(|e| std::cmp::Reverse(e.timestamp)); // Sort back to newest first
+    if plan.evictions.is_empty() {
+        println!("Nothing to prune ({} in use)", format_size(plan.size_before));
+        return Ok(());
     }
 
-    // Calculate total size and remove oldest if exceeding size limit
-    let mut total_size: u64 = index.entries.iter().map(|e| e.file_size as u64).sum();
-    let max_size_bytes = MAX_CACHE_SIZE_MB * 1024 * 1024;
+    let verb = if args.dry_run { "Would remove" } else { "Removed" };
+    for eviction in &plan.evictions {
+        println!("{} {}: {}", verb, eviction.filename, eviction.reason.dimmed());
+    }
+    println!(
+        "{} {} sessions, {} -> {}",
+        verb,
+        plan.evictions.len(),
+        format_size(plan.size_before),
+        format_size(plan.size_after)
+    );
 
-    if total_size > max_size_bytes {
-        index.entries.sort_by_key(|e| e.timestamp);
-        while total_size > max_size_bytes && !index.entries.is_empty() {
-            let oldest_filename = if let Some(oldest) = index.entries.first() {
-                total_size -= oldest.file_size as u64;
-                oldest.filename.clone()
-            } else {
-                break;
-            };
+    Ok(())
+}
 
-            index.entries.remove(0);
+/// Size on disk of every blob, by hash.
+fn blob_sizes(cache_dir: &Path) -> HashMap<String, u64> {
+    fs::read_dir(blobs_dir(cache_dir))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_str()?.strip_suffix(".zst")?.to_string();
+                    Some((name, entry.metadata().ok()?.len()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
 
-            // Remove actual cache file
-            let cache_file = sessions_dir.join(&oldest_filename);
-            let _ = fs::remove_file(cache_file);
-        }
-        index.entries.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
     }
+}
 
-    // Remove cache files that are no longer in index
-    let cached_files: std::collections::HashSet<String> = index.entries.iter()
-        .map(|e| e.filename.clone())
-        .collect();
+/// Lists the first `max` paths, noting how many more there are.
+fn format_file_list(files: &[String], max: usize) -> String {
+    let mut list = files.iter().take(max).cloned().collect::<Vec<_>>().join(", ");
+    if files.len() > max {
+        list.push_str(&format!(" +{} more", files.len() - max));
+    }
+    list
+}
 
-    if let Ok(entries) = fs::read_dir(&sessions_dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                if let Some(filename) = entry.file_name().to_str() {
-                    if filename.ends_with(".cache") && !cached_files.contains(filename) {
-                        let _ = fs::remove_file(entry.path());
-                    }
-                }
-            }
-        }
+fn format_entry_summary(entry: &CacheIndexEntry) -> String {
+    let local_time = entry.timestamp.with_timezone(&Local);
+    // Use home directory replacement for cleaner paths
+    let working_dir = display_path(&entry.working_dir);
+    let mut summary = format!(
+        "{} | {} chars | {} tokens | {} files | {}",
+        local_time.format("%d %b %Y %H:%M"),
+        entry.file_size,
+        entry.token_count.to_string().cyan(),
+        entry.source_file_count,
+        working_dir
+    );
+    if entry.metadata_only {
+        summary.push_str(&format!(" | {}", "metadata only".dimmed()));
+    }
+    let labels = entry.labels.summary();
+    if !labels.is_empty() {
+        summary.push_str(&format!(" | {}", labels.yellow()));
     }
+    summary
+}
 
-    // Write updated index
-    let serialized = serde_json::to_string(&index).context("Failed to serialize cleaned index")?;
-    fs::write(&index_path, serialized).context("Failed to write cleaned index")?;
+/// Orders sessions sorted newest first by project, starting with the most
+/// recently used one. Session positions used as IDs refer to this order.
+fn group_by_project(entries: Vec<CacheIndexEntry>) -> Vec<CacheIndexEntry> {
+    let mut groups: Vec<(String, Vec<CacheIndexEntry>)> = Vec::new();
+    for entry in entries {
+        match groups.iter_mut().find(|(root, _)| *root == entry.project_root) {
+            Some((_, group)) => group.push(entry),
+            None => groups.push((entry.project_root.clone(), vec![entry])),
+        }
+    }
+    groups.into_iter().flat_map(|(_, group)| group).collect()
+}
 
-    Ok(())
+/// A session as shown in interactive lists, prefixed with its project when
+/// sessions of several projects are listed.
+fn selection_label(position: usize, entry: &CacheIndexEntry, show_project: bool) -> String {
+    if show_project {
+        format!(
+            "[{:02}] {} | {}",
+            position,
+            display_path(&entry.project_root).bold(),
+            format_entry_summary(entry)
+        )
+    } else {
+        format!("[{:02}] {}", position, format_entry_summary(entry))
+    }
 }
 
-pub fn interactive_cache_selection(cache_dir_override: &Option<String>, osc52: bool) -> Result<()> {
-    let entries = list_cache_entries(cache_dir_override)?;
+/// Lets the user pick a session of the project `project_root`, or of any
+/// project for `None`, then preview it and choose what to do with it.
+pub fn interactive_cache_selection(
+    cache_dir_override: &Option<String>,
+    project_root: Option<&str>,
+    osc52: bool,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = CacheIndex { entries: list_cache_entries(cache_dir_override, keyring)? }.for_project(project_root);
 
     if entries.is_empty() {
-        println!("No cached entries found.");
+        match project_root {
+            Some(root) => println!(
+                "No cached entries found for {} (use --all-projects to see other projects).",
+                display_path(root)
+            ),
+            None => println!("No cached entries found."),
+        }
         return Ok(());
     }
 
     // Create selection options with simplified info
     let options: Vec<String> = entries.iter()
         .enumerate()
-        .map(|(i, entry)| {
-            let local_time = entry.timestamp.with_timezone(&chrono::Local);
-            // Use home directory replacement for cleaner paths
-            let working_dir = entry.working_dir.replace(&env::var("HOME").unwrap_or_default(), "~");
-            format!(
-                "[{:02}] {} | {} chars | {} tokens | {} files | {}",
-                i + 1,
-                local_time.format("%d %b %Y %H:%M"),
-                entry.file_size,
-                entry.token_count.to_string().cyan(),
-                entry.source_file_count,
-                working_dir
-            )
-        })
+        .map(|(i, entry)| selection_label(i + 1, entry, project_root.is_none()))
         .collect();
 
-    let selected = Select::new("Select a cache entry to copy to clipboard:", options.clone())
+    let message = match project_root {
+        Some(root) => format!("Select a cache entry from {} to copy to clipboard:", display_path(root)),
+        None => "Select a cache entry to copy to clipboard:".to_string(),
+    };
+    let selected = Select::new(&message, options.clone())
         .with_page_size(10)
         .prompt();
 
@@ -368,13 +1160,10 @@ pub fn interactive_cache_selection(cache_dir_override: &Option<String>, osc52: b
                 .ok_or_else(|| anyhow::anyhow!("Could not parse selection index"))?;
 
             if let Some(selected_entry) = entries.get(selected_index) {
-                let cache_dir = get_cache_dir(cache_dir_override)?;
-                let cache_file_path = cache_dir.join("sessions").join(&selected_entry.filename);
-                let cache_content = fs::read_to_string(&cache_file_path).context("Failed to read cache file")?;
-                let entry: CacheEntry = serde_json::from_str(&cache_content).context("Failed to parse cache entry")?;
+                let entry = load_cache_entry(&cache_dir, &selected_entry.filename, keyring)?;
 
-                copy_cache_to_clipboard(&entry, osc52)?;
-                println!("✓ Selected cache entry copied to clipboard!");
+                print_cache_preview(&selected_entry.filename, &entry, PREVIEW_LINES);
+                prompt_cache_action(&cache_dir, &selected_entry.filename, &entry, osc52, keyring)?;
             }
         }
         Err(inquire::InquireError::OperationCanceled) => {
@@ -390,3 +1179,1600 @@ pub fn interactive_cache_selection(cache_dir_override: &Option<String>, osc52: b
 
     Ok(())
 }
+
+/// A session as printed by `cache ls --json`.
+#[derive(Serialize)]
+struct ListedSession<'a> {
+    /// Position usable as a session ID.
+    id: usize,
+    #[serde(flatten)]
+    entry: &'a CacheIndexEntry,
+}
+
+/// Prints the sessions of the project `project_root`, or of every project for
+/// `None`, as a plain table or as JSON, for scripts and non-interactive use.
+pub fn print_cache_list(
+    cache_dir_override: &Option<String>,
+    args: &CacheLsArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let entries = CacheIndex { entries: list_cache_entries(cache_dir_override, keyring)? }.for_project(project_root);
+    let listed: Vec<ListedSession> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| ListedSession { id: i + 1, entry })
+        .collect();
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&listed).context("Failed to serialize cache list")?;
+        return ignore_broken_pipe(writeln!(std::io::stdout().lock(), "{}", json))
+            .context("Failed to write to stdout");
+    }
+    if listed.is_empty() {
+        eprintln!("No cached entries found.");
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 7]> = listed
+        .iter()
+        .map(|listed| {
+            let entry = listed.entry;
+            let mut labels = entry.labels.summary();
+            if entry.metadata_only {
+                if !labels.is_empty() {
+                    labels.push(' ');
+                }
+                labels.push_str("metadata-only");
+            }
+            [
+                listed.id.to_string(),
+                entry.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+                entry.token_count.to_string(),
+                entry.source_file_count.to_string(),
+                entry.file_size.to_string(),
+                display_path(&entry.working_dir),
+                labels,
+            ]
+        })
+        .collect();
+    let header = ["ID", "DATE", "TOKENS", "FILES", "CHARS", "DIRECTORY", "LABELS"];
+    ignore_broken_pipe(write_table(&mut std::io::stdout().lock(), header, rows)).context("Failed to write to stdout")
+}
+
+/// Writes rows under `header` in aligned columns, numbers right-aligned.
+fn write_table(out: &mut impl Write, header: [&str; 7], rows: Vec<[String; 7]>) -> std::io::Result<()> {
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| rows.iter().map(|row| row[column].len()).chain([header[column].len()]).max().unwrap_or(0))
+        .collect();
+
+    for row in std::iter::once(header.map(String::from)).chain(rows) {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(column, (cell, width))| match column {
+                0 | 2..=4 => format!("{:>width$}", cell, width = width),
+                _ => format!("{:<width$}", cell, width = width),
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    Ok(())
+}
+
+/// Treats a reader that stops early, like `head`, as the end of the output
+/// rather than an error.
+fn ignore_broken_pipe(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// Parses `YYYY-MM-DD` (local time) or a relative age such as `7d` or `12h`.
+/// Dates used as an upper bound cover the whole day.
+fn parse_date_bound(value: &str, end_of_day: bool) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    let days = value.strip_suffix('d').and_then(|n| n.parse().ok()).map(chrono::TimeDelta::try_days);
+    let hours = value.strip_suffix('h').and_then(|n| n.parse().ok()).map(chrono::TimeDelta::try_hours);
+    if let Some(age) = days.or(hours) {
+        return age
+            .and_then(|age| Utc::now().checked_sub_signed(age))
+            .ok_or_else(|| anyhow::anyhow!("Invalid date '{}', expected YYYY-MM-DD or e.g. 7d", value));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date '{}', expected YYYY-MM-DD or e.g. 7d", value))?;
+    let time = if end_of_day {
+        date.and_hms_opt(23, 59, 59)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    }
+    .ok_or_else(|| anyhow::anyhow!("Invalid date '{}'", value))?;
+
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or_else(|| anyhow::anyhow!("Invalid local date '{}'", value))
+}
+
+/// Returns up to `max` lines of `content` containing a match, trimmed around the first match.
+fn content_snippets(content: &str, pattern: &Regex, max: usize) -> Vec<String> {
+    const CONTEXT_CHARS: usize = 40;
+
+    content
+        .lines()
+        .filter_map(|line| pattern.find(line).map(|m| (line, m)))
+        .take(max)
+        .map(|(line, m)| {
+            let mut start = m.start().saturating_sub(CONTEXT_CHARS);
+            while !line.is_char_boundary(start) {
+                start -= 1;
+            }
+            let mut end = (m.end() + CONTEXT_CHARS).min(line.len());
+            while !line.is_char_boundary(end) {
+                end += 1;
+            }
+            format!(
+                "{}{}{}{}{}",
+                if start > 0 { "…" } else { "" },
+                line[start..m.start()].trim_start(),
+                m.as_str().yellow().bold(),
+                line[m.end()..end].trim_end(),
+                if end < line.len() { "…" } else { "" }
+            )
+        })
+        .collect()
+}
+
+pub fn search_cache(
+    cache_dir_override: &Option<String>,
+    args: &CacheSearchArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = CacheIndex { entries: list_cache_entries(cache_dir_override, keyring)? }.for_project(project_root);
+
+    let since = args.since.as_deref().map(|s| parse_date_bound(s, false)).transpose()?;
+    let until = args.until.as_deref().map(|s| parse_date_bound(s, true)).transpose()?;
+    let pattern = match &args.query {
+        Some(query) if args.regex => Some(Regex::new(query).context("Invalid search pattern")?),
+        Some(query) => Some(Regex::new(&format!("(?i){}", regex::escape(query)))?),
+        None => None,
+    };
+
+    let mut matches = 0;
+    for index_entry in &entries {
+        if since.is_some_and(|since| index_entry.timestamp < since)
+            || until.is_some_and(|until| index_entry.timestamp > until)
+            || args.min_tokens.is_some_and(|min| index_entry.token_count < min)
+            || args.max_tokens.is_some_and(|max| index_entry.token_count > max)
+        {
+            continue;
+        }
+        if let Some(dir) = &args.dir {
+            if !index_entry.working_dir.contains(dir.as_str()) {
+                continue;
+            }
+        }
+        if let Some(tag) = &args.tag {
+            if !index_entry.labels.tags.contains(tag) {
+                continue;
+            }
+        }
+
+        let snippets = match &pattern {
+            Some(pattern) => {
+                let entry = match load_cache_entry(&cache_dir, &index_entry.filename, keyring) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        eprintln!("Warning: Skipping {}: {}", index_entry.filename, e);
+                        continue;
+                    }
+                };
+                let snippets = content_snippets(&entry.content, pattern, 3);
+                if snippets.is_empty() {
+                    continue;
+                }
+                snippets
+            }
+            None => Vec::new(),
+        };
+
+        matches += 1;
+        println!("{} {}", index_entry.filename.dimmed(), format_entry_summary(index_entry));
+        if !index_entry.files.is_empty() {
+            println!("    {}", format_file_list(&index_entry.files, 5).dimmed());
+        }
+        for snippet in snippets {
+            println!("    {}", snippet);
+        }
+
+        if matches >= args.limit {
+            break;
+        }
+    }
+
+    if matches == 0 {
+        println!("No matching cache entries found.");
+    }
+
+    Ok(())
+}
+
+/// Reads the index, rebuilding it from the session files when it is missing
+/// or unreadable. Entries whose session file is gone are left out.
+///
+/// A rebuilt index is only kept in memory; it is saved by the next change to
+/// the cache, which holds the lock.
+fn read_cache_index(cache_dir: &Path, keyring: &Keyring) -> Result<CacheIndex> {
+    let sessions_dir = cache_dir.join("sessions");
+    let mut index = match parse_cache_index(cache_dir) {
+        Ok(Some(index)) => index,
+        Ok(None) => rebuild_cache_index(cache_dir, keyring),
+        Err(e) => {
+            eprintln!("Warning: {:#}; rebuilding it from the session files", e);
+            rebuild_cache_index(cache_dir, keyring)
+        }
+    };
+    index.entries.retain(|e| sessions_dir.join(&e.filename).exists());
+
+    Ok(index)
+}
+
+/// Reads the index file as it is, or `None` if there isn't one.
+fn parse_cache_index(cache_dir: &Path) -> Result<Option<CacheIndex>> {
+    let index_path = cache_dir.join("sessions").join("cache_index.json");
+    if !index_path.exists() {
+        return Ok(None);
+    }
+
+    let index_content = fs::read_to_string(&index_path).context("Failed to read cache index")?;
+    let mut index: CacheIndex = serde_json::from_str(&index_content).context("Failed to parse cache index")?;
+    index.migrate();
+
+    Ok(Some(index))
+}
+
+fn rebuild_cache_index(cache_dir: &Path, keyring: &Keyring) -> CacheIndex {
+    let mut index = CacheIndex { entries: vec![] };
+    reconcile_index(cache_dir, &mut index, false, keyring);
+    index
+}
+
+/// Something found wrong with the cache.
+pub enum CacheProblem {
+    UnreadableIndex(String),
+    DuplicateEntry(String),
+    MissingSession(String),
+    UnindexedSession(String),
+    UnreadableSession(String, String),
+    UndecryptableSession(String, String),
+}
+
+impl CacheProblem {
+    fn describe(&self) -> String {
+        match self {
+            CacheProblem::UnreadableIndex(error) => format!("index is unreadable ({})", error),
+            CacheProblem::DuplicateEntry(filename) => format!("{} is listed in the index more than once", filename),
+            CacheProblem::MissingSession(filename) => format!("{} is listed in the index but its file is missing", filename),
+            CacheProblem::UnindexedSession(filename) => format!("{} is not listed in the index", filename),
+            CacheProblem::UnreadableSession(filename, error) => format!("{} is unreadable ({})", filename, error),
+            CacheProblem::UndecryptableSession(filename, error) => {
+                format!("{} can't be decrypted ({})", filename, error)
+            }
+        }
+    }
+
+    fn fix(&self) -> &'static str {
+        match self {
+            CacheProblem::UnreadableIndex(_) => "rebuilt the index from the session files",
+            CacheProblem::DuplicateEntry(_) => "removed the extra entries",
+            CacheProblem::MissingSession(_) => "removed it from the index",
+            CacheProblem::UnindexedSession(_) => "added it to the index",
+            CacheProblem::UnreadableSession(_, _) => "moved it to quarantine",
+            CacheProblem::UndecryptableSession(_, _) => "left it as it is",
+        }
+    }
+}
+
+/// Brings the index in line with the session files on disk: drops duplicate
+/// and dangling entries, adds readable sessions the index doesn't know about
+/// and reports unreadable ones, which the caller should quarantine. Sessions
+/// that can't be decrypted are reported but left alone.
+///
+/// With `verify` every indexed session is also fully loaded, blobs included.
+fn reconcile_index(cache_dir: &Path, index: &mut CacheIndex, verify: bool, keyring: &Keyring) -> Vec<CacheProblem> {
+    let sessions_dir = cache_dir.join("sessions");
+    let mut problems = Vec::new();
+
+    let mut seen = HashSet::new();
+    index.entries.retain(|e| {
+        if seen.insert(e.filename.clone()) {
+            return true;
+        }
+        problems.push(CacheProblem::DuplicateEntry(e.filename.clone()));
+        false
+    });
+
+    index.entries.retain(|e| {
+        if sessions_dir.join(&e.filename).exists() {
+            return true;
+        }
+        problems.push(CacheProblem::MissingSession(e.filename.clone()));
+        false
+    });
+
+    if verify {
+        index.entries.retain(|e| match load_cache_entry(cache_dir, &e.filename, keyring) {
+            Ok(_) => true,
+            Err(error) if encryption::is_decrypt_error(&error) => {
+                problems.push(CacheProblem::UndecryptableSession(e.filename.clone(), format!("{:#}", error)));
+                true
+            }
+            Err(error) => {
+                problems.push(CacheProblem::UnreadableSession(e.filename.clone(), format!("{:#}", error)));
+                false
+            }
+        });
+    }
+
+    let mut unindexed: Vec<String> = fs::read_dir(&sessions_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| entry.file_name().to_str().map(String::from))
+                .filter(|name| name.ends_with(".cache") && !seen.contains(name))
+                .collect()
+        })
+        .unwrap_or_default();
+    unindexed.sort();
+
+    for filename in unindexed {
+        let loaded = read_stored_entry(&sessions_dir.join(&filename), keyring).and_then(|mut stored| {
+            if verify {
+                load_cache_entry(cache_dir, &filename, keyring)?;
+            }
+            stored.migrate();
+            Ok(stored)
+        });
+        match loaded {
+            Ok(stored) => {
+                index.entries.push(index_entry_for(cache_dir, &stored, &filename));
+                problems.push(CacheProblem::UnindexedSession(filename));
+            }
+            Err(error) if encryption::is_decrypt_error(&error) => {
+                problems.push(CacheProblem::UndecryptableSession(filename, format!("{:#}", error)))
+            }
+            Err(error) => problems.push(CacheProblem::UnreadableSession(filename, format!("{:#}", error))),
+        }
+    }
+
+    index.entries.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+    problems
+}
+
+/// Moves a session file out of the way into the cache's quarantine directory.
+fn quarantine_session(cache_dir: &Path, filename: &str) -> Result<PathBuf> {
+    let quarantine_dir = cache_dir.join(QUARANTINE_DIR_NAME);
+    create_private_dir(&quarantine_dir).context("Failed to create quarantine directory")?;
+
+    let mut destination = quarantine_dir.join(filename);
+    let mut counter = 1;
+    while destination.exists() {
+        counter += 1;
+        destination = quarantine_dir.join(format!("{}.{}", filename, counter));
+    }
+    fs::rename(cache_dir.join("sessions").join(filename), &destination)
+        .context("Failed to quarantine cache file")?;
+
+    Ok(destination)
+}
+
+/// Checks the index against the session files and blobs, and repairs what it can.
+pub fn doctor_cache(cache_dir_override: &Option<String>, args: &CacheDoctorArgs, keyring: &Keyring) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let _lock = lock_cache(&cache_dir)?;
+
+    let mut problems = Vec::new();
+    let mut index = match parse_cache_index(&cache_dir) {
+        Ok(Some(index)) => index,
+        Ok(None) => CacheIndex { entries: vec![] },
+        Err(e) => {
+            problems.push(CacheProblem::UnreadableIndex(format!("{:#}", e)));
+            CacheIndex { entries: vec![] }
+        }
+    };
+    let rebuilt = !problems.is_empty();
+    // Every session is unindexed when the index is rebuilt, which isn't worth listing
+    problems.extend(
+        reconcile_index(&cache_dir, &mut index, true, keyring)
+            .into_iter()
+            .filter(|problem| !(rebuilt && matches!(problem, CacheProblem::UnindexedSession(_)))),
+    );
+
+    let referenced: HashSet<&str> = index.entries.iter().flat_map(|e| e.blobs.iter().map(String::as_str)).collect();
+    let unreferenced_blobs = fs::read_dir(blobs_dir(&cache_dir))
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| {
+                    let name = entry.file_name();
+                    name.to_str()
+                        .and_then(|n| n.strip_suffix(".zst"))
+                        .is_some_and(|hash| !referenced.contains(hash))
+                })
+                .count()
+        })
+        .unwrap_or(0);
+
+    println!("Checked {} sessions in {}", index.entries.len(), cache_dir.display());
+    for problem in &problems {
+        if args.dry_run {
+            println!("{} {}", "✗".red(), problem.describe());
+        } else {
+            println!("{} {}: {}", "✗".red(), problem.describe(), problem.fix().dimmed());
+        }
+    }
+    if unreferenced_blobs > 0 {
+        println!("{} {} unreferenced blobs", "✗".red(), unreferenced_blobs);
+    }
+
+    if problems.is_empty() && unreferenced_blobs == 0 {
+        println!("{} No problems found", "✓".green());
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("Nothing was changed (dry run)");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        if let CacheProblem::UnreadableSession(filename, _) = problem {
+            let destination = quarantine_session(&cache_dir, filename)?;
+            println!("  {} -> {}", filename, destination.display());
+        }
+    }
+    if !problems.iter().any(|p| matches!(p, CacheProblem::UndecryptableSession(_, _))) {
+        remove_unreferenced_blobs(&cache_dir, &index);
+    }
+    write_cache_index(&cache_dir, &index)?;
+    println!("{} Cache repaired", "✓".green());
+
+    Ok(())
+}
+
+fn write_cache_index(cache_dir: &Path, index: &CacheIndex) -> Result<()> {
+    let index_path = cache_dir.join("sessions").join("cache_index.json");
+    let serialized = serde_json::to_string(index).context("Failed to serialize cache index")?;
+    write_atomic(&index_path, serialized.as_bytes()).context("Failed to write cache index")?;
+
+    Ok(())
+}
+
+/// Resolves a session selector: a position in `entries`, `-N` for the N-th
+/// most recent session, a file name, a name or a timestamp prefix. Positions
+/// and timestamps only count sessions of `project_root` when one is given,
+/// matching the numbers `cache ls` shows; file names and names are unique
+/// across projects.
+fn resolve_cache_entry<'a>(
+    entries: &'a [CacheIndexEntry],
+    id: &str,
+    project_root: Option<&str>,
+) -> Result<&'a CacheIndexEntry> {
+    let scoped: Vec<&CacheIndexEntry> = entries
+        .iter()
+        .filter(|e| project_root.is_none_or(|root| e.project_root == root))
+        .collect();
+
+    if let Ok(position) = id.parse::<usize>() {
+        return position
+            .checked_sub(1)
+            .and_then(|i| scoped.get(i).copied())
+            .ok_or_else(|| anyhow::anyhow!("No cache entry at position {}", position));
+    }
+    if let Some(back) = id.strip_prefix('-').and_then(|n| n.parse::<usize>().ok()) {
+        let mut by_time = scoped;
+        by_time.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+        return back
+            .checked_sub(1)
+            .and_then(|i| by_time.get(i).copied())
+            .ok_or_else(|| match project_root {
+                Some(root) => anyhow::anyhow!("There are fewer than {} cache entries for {}", back, display_path(root)),
+                None => anyhow::anyhow!("There are fewer than {} cache entries", back),
+            });
+    }
+
+    let filename = if id.ends_with(".cache") {
+        id.to_string()
+    } else {
+        format!("{}.cache", id)
+    };
+    entries
+        .iter()
+        .find(|e| e.filename == filename)
+        .or_else(|| entries.iter().find(|e| e.labels.name.as_deref() == Some(id)))
+        .or_else(|| {
+            scoped
+                .into_iter()
+                .filter(|e| matches_timestamp_prefix(e, id))
+                .max_by_key(|e| e.timestamp)
+        })
+        .ok_or_else(|| anyhow::anyhow!("No cache entry named '{}'", id))
+}
+
+/// Whether `prefix` is the start of the session's local time as shown by
+/// `cache ls` (`2026-03-01 14:05:09`, with `T`, `_` or `-` also accepted as
+/// separators) or of its file name.
+fn matches_timestamp_prefix(entry: &CacheIndexEntry, prefix: &str) -> bool {
+    if !prefix.starts_with(|c: char| c.is_ascii_digit()) {
+        return false;
+    }
+    let normalized = match prefix.split_once([' ', 'T', '_']) {
+        Some((date, time)) => format!("{} {}", date, time.replace('-', ":")),
+        None => prefix.to_string(),
+    };
+    let local_time = entry.timestamp.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string();
+    local_time.starts_with(&normalized) || entry.filename.starts_with(prefix)
+}
+
+/// Changes a session's labels in both the index and its session file.
+fn update_labels(
+    cache_dir: &Path,
+    id: &str,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+    update: impl FnOnce(&mut SessionLabels),
+) -> Result<(String, SessionLabels)> {
+    let _lock = lock_cache(cache_dir)?;
+    let mut index = read_cache_index(cache_dir, keyring)?;
+    index.entries.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+    index.entries = group_by_project(index.entries);
+    let filename = resolve_cache_entry(&index.entries, id, project_root)?.filename.clone();
+
+    let session_path = cache_dir.join("sessions").join(&filename);
+    let mut stored = read_stored_entry(&session_path, keyring)?;
+    update(&mut stored.labels);
+    write_stored_entry(cache_dir, &session_path, &stored, keyring)?;
+
+    for entry in index.entries.iter_mut().filter(|e| e.filename == filename) {
+        entry.labels = stored.labels.clone();
+    }
+    write_cache_index(cache_dir, &index)?;
+
+    Ok((filename, stored.labels))
+}
+
+pub fn name_cache_entry(
+    cache_dir_override: &Option<String>,
+    args: &CacheNameArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+
+    if let Some(name) = &args.name {
+        // Names must not be mistaken for positions such as `3` or `-2`
+        if name.trim().is_empty() || name.strip_prefix('-').unwrap_or(name).parse::<usize>().is_ok() {
+            return Err(anyhow::anyhow!("'{}' can't be used as a name", name));
+        }
+        let entries = list_cache_entries(cache_dir_override, keyring)?;
+        let target = &resolve_cache_entry(&entries, &args.id, project_root)?.filename;
+        if let Some(other) = entries.iter().find(|e| e.labels.name.as_ref() == Some(name) && &e.filename != target) {
+            return Err(anyhow::anyhow!("The name '{}' is already used by {}", name, other.filename));
+        }
+    }
+
+    let (filename, _) = update_labels(&cache_dir, &args.id, project_root, keyring, |labels| {
+        labels.name = args.name.clone()
+    })?;
+    match &args.name {
+        Some(name) => println!("Named {} '{}'", filename, name),
+        None => println!("Removed the name of {}", filename),
+    }
+
+    Ok(())
+}
+
+pub fn tag_cache_entry(
+    cache_dir_override: &Option<String>,
+    args: &CacheTagArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let (filename, labels) = update_labels(&cache_dir, &args.id, project_root, keyring, |labels| {
+        if args.remove {
+            labels.tags.retain(|tag| !args.tags.contains(tag));
+        } else {
+            for tag in &args.tags {
+                if !labels.tags.contains(tag) {
+                    labels.tags.push(tag.clone());
+                }
+            }
+        }
+    })?;
+
+    if labels.tags.is_empty() {
+        println!("{} has no tags", filename);
+    } else {
+        println!("Tags of {}: {}", filename, labels.tags.join(", "));
+    }
+
+    Ok(())
+}
+
+pub fn pin_cache_entry(
+    cache_dir_override: &Option<String>,
+    id: &str,
+    project_root: Option<&str>,
+    pinned: bool,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let (filename, _) = update_labels(&cache_dir, id, project_root, keyring, |labels| labels.pinned = pinned)?;
+    if pinned {
+        println!("Pinned {}; it will not be removed by automatic cleanup", filename);
+    } else {
+        println!("Unpinned {}", filename);
+    }
+
+    Ok(())
+}
+
+fn delete_cache_entry(cache_dir: &Path, filename: &str, keyring: &Keyring) -> Result<()> {
+    delete_cache_entries(cache_dir, &[filename.to_string()], keyring)
+}
+
+/// Removes sessions from the index and disk under one lock, then the blobs
+/// no remaining session refers to.
+fn delete_cache_entries(cache_dir: &Path, filenames: &[String], keyring: &Keyring) -> Result<()> {
+    let _lock = lock_cache(cache_dir)?;
+    let mut index = read_cache_index(cache_dir, keyring)?;
+    index.entries.retain(|e| !filenames.contains(&e.filename));
+    write_cache_index(cache_dir, &index)?;
+
+    for filename in filenames {
+        let cache_file = cache_dir.join("sessions").join(filename);
+        if cache_file.exists() {
+            fs::remove_file(&cache_file).context("Failed to remove cache file")?;
+        }
+    }
+    remove_unreferenced_blobs(cache_dir, &index);
+
+    Ok(())
+}
+
+/// Deletes the sessions given by ID, those matching the filters, or those
+/// picked from a list. Filters and the list only cover the sessions of
+/// `project_root` when one is given.
+pub fn remove_cache_entries(
+    cache_dir_override: &Option<String>,
+    args: &CacheRmArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = list_cache_entries(cache_dir_override, keyring)?;
+    let filtered = args.dir.is_some() || args.older_than.is_some() || args.tag.is_some();
+
+    let mut selected: Vec<&CacheIndexEntry> = if !args.ids.is_empty() {
+        args.ids.iter().map(|id| resolve_cache_entry(&entries, id, project_root)).collect::<Result<_>>()?
+    } else {
+        if !filtered && !args.interactive {
+            return Err(anyhow::anyhow!(
+                "Give the sessions to delete, a filter (--dir, --older-than, --tag) or --interactive"
+            ));
+        }
+        let older_than = args.older_than.as_deref().map(|v| parse_date_bound(v, false)).transpose()?;
+        // Keep the numbers `cache ls` shows for the list
+        let matching: Vec<(usize, &CacheIndexEntry)> = entries
+            .iter()
+            .filter(|e| project_root.is_none_or(|root| e.project_root == root))
+            .enumerate()
+            .map(|(i, e)| (i + 1, e))
+            .filter(|(_, e)| {
+                args.dir.as_ref().is_none_or(|dir| e.working_dir.contains(dir.as_str()))
+                    && older_than.is_none_or(|bound| e.timestamp < bound)
+                    && args.tag.as_ref().is_none_or(|tag| e.labels.tags.contains(tag))
+            })
+            .collect();
+
+        if args.interactive {
+            pick_cache_entries(matching, project_root.is_none())?
+        } else {
+            let (pinned, unpinned): (Vec<_>, Vec<_>) = matching
+                .into_iter()
+                .map(|(_, e)| e)
+                .partition(|e| e.labels.pinned && !args.include_pinned);
+            if !pinned.is_empty() {
+                println!(
+                    "Skipping {} pinned sessions (use --include-pinned to delete them too)",
+                    pinned.len()
+                );
+            }
+            unpinned
+        }
+    };
+    let mut seen = HashSet::new();
+    selected.retain(|e| seen.insert(e.filename.as_str()));
+
+    if selected.is_empty() {
+        println!("No matching cache entries found.");
+        return Ok(());
+    }
+
+    let verb = if args.dry_run { "Would delete" } else { "Deleting" };
+    for entry in &selected {
+        println!("{} {} {}", verb, entry.filename.dimmed(), format_entry_summary(entry));
+    }
+    if args.dry_run {
+        println!("Nothing was deleted (dry run)");
+        return Ok(());
+    }
+
+    // Sessions matched by a filter may be more than the user expects
+    if filtered && !args.interactive && !args.yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow::anyhow!(
+                "Pass --yes to delete {} sessions without a terminal",
+                selected.len()
+            ));
+        }
+        let confirmed = inquire::Confirm::new(&format!("Delete {} sessions?", selected.len()))
+            .with_default(false)
+            .prompt()
+            .unwrap_or(false);
+        if !confirmed {
+            println!("Nothing was deleted.");
+            return Ok(());
+        }
+    }
+
+    let filenames: Vec<String> = selected.iter().map(|e| e.filename.clone()).collect();
+    delete_cache_entries(&cache_dir, &filenames, keyring)?;
+    println!("Deleted {} sessions", filenames.len());
+
+    Ok(())
+}
+
+/// Lets the user tick any number of sessions, given with their numbers, in a list.
+fn pick_cache_entries(
+    entries: Vec<(usize, &CacheIndexEntry)>,
+    show_project: bool,
+) -> Result<Vec<&CacheIndexEntry>> {
+    if entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!("--interactive needs a terminal"));
+    }
+
+    let options: Vec<String> = entries
+        .iter()
+        .map(|(position, entry)| selection_label(*position, entry, show_project))
+        .collect();
+    let picked = match MultiSelect::new("Select the sessions to delete:", options)
+        .with_page_size(10)
+        .raw_prompt()
+    {
+        Ok(picked) => picked,
+        Err(inquire::InquireError::OperationCanceled) | Err(inquire::InquireError::OperationInterrupted) => {
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(picked.into_iter().map(|option| entries[option.index].1).collect())
+}
+
+fn print_cache_preview(filename: &str, entry: &CacheEntry, lines: usize) {
+    let local_time = entry.timestamp.with_timezone(&Local);
+    let working_dir = display_path(&entry.working_dir);
+
+    println!("{} {}", "Session:".bold(), filename);
+    println!("{} {}", "Date:".bold(), local_time.format("%d %b %Y %H:%M:%S"));
+    println!("{} {}", "Directory:".bold(), working_dir);
+    let counter = match entry.token_counter.as_deref() {
+        Some("estimate") | None => String::new(),
+        Some(counter) => format!(" ({})", counter),
+    };
+    println!(
+        "{} {}{}{}",
+        "Tokens:".bold(),
+        token_prefix(entry),
+        entry.token_count.to_string().cyan(),
+        counter
+    );
+    println!("{} {} chars from {} files", "Size:".bold(), entry.file_size, entry.source_file_count);
+    println!("{} {}", "Options:".bold(), entry.options.summary());
+    let labels = entry.labels.summary();
+    if !labels.is_empty() {
+        println!("{} {}", "Labels:".bold(), labels.yellow());
+    }
+
+    if !entry.files.is_empty() {
+        println!("{}", "Files:".bold());
+        for file in entry.files.iter().take(MAX_PREVIEW_FILES) {
+            let modified = file
+                .modified
+                .map(|m| m.with_timezone(&Local).format("%d %b %Y %H:%M").to_string())
+                .unwrap_or_else(|| "-".to_string());
+            let hash = file.hash.as_deref().map(|h| &h[..h.len().min(12)]).unwrap_or("-");
+            println!(
+                "  {}  {} bytes  {}  {}",
+                file.path,
+                file.size,
+                modified.dimmed(),
+                hash.dimmed()
+            );
+        }
+        if entry.files.len() > MAX_PREVIEW_FILES {
+            println!("  … and {} more", entry.files.len() - MAX_PREVIEW_FILES);
+        }
+    }
+
+    if entry.metadata_only {
+        println!("{}", "--- metadata only, content not cached ---".dimmed());
+        return;
+    }
+    let total_lines = entry.content.lines().count();
+    println!("{}", format!("--- first {} of {} lines ---", lines.min(total_lines), total_lines).dimmed());
+    for line in entry.content.lines().take(lines) {
+        println!("{}", line);
+    }
+    println!("{}", "---".dimmed());
+}
+
+fn prompt_cache_action(
+    cache_dir: &Path,
+    filename: &str,
+    entry: &CacheEntry,
+    osc52: bool,
+    keyring: &Keyring,
+) -> Result<()> {
+    const COPY: &str = "Copy to clipboard";
+    const WRITE: &str = "Write to file";
+    const DELETE: &str = "Delete";
+    const CANCEL: &str = "Cancel";
+
+    let action = match Select::new("What do you want to do with this entry?", vec![COPY, WRITE, DELETE, CANCEL]).prompt() {
+        Ok(action) => action,
+        Err(inquire::InquireError::OperationCanceled) | Err(inquire::InquireError::OperationInterrupted) => CANCEL,
+        Err(e) => return Err(e.into()),
+    };
+
+    match action {
+        COPY => {
+            copy_cache_to_clipboard(entry, osc52)?;
+            println!("✓ Selected cache entry copied to clipboard!");
+        }
+        WRITE => {
+            let path = Text::new("Output file (.txt):").prompt()?;
+            write_cache_to_file(entry, Path::new(&path))?;
+        }
+        DELETE => {
+            delete_cache_entry(cache_dir, filename, keyring)?;
+            println!("Deleted cache entry {}", filename);
+        }
+        _ => println!("Selection cancelled."),
+    }
+
+    Ok(())
+}
+
+pub fn write_cache_to_file(entry: &CacheEntry, path: &Path) -> Result<()> {
+    entry.require_content()?;
+    write_output_file(path, &entry.content).map_err(|e| anyhow::anyhow!("Failed to write output file: {}", e))?;
+    println!(
+        "Wrote {} characters ({}{} tokens) to {}",
+        entry.content.len(),
+        token_prefix(entry),
+        entry.token_count.to_string().cyan(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Loads the entry identified by `id`, returning its file name alongside it.
+pub fn find_cache_entry(
+    cache_dir_override: &Option<String>,
+    id: &str,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<(String, CacheEntry)> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = list_cache_entries(cache_dir_override, keyring)?;
+    let index_entry = resolve_cache_entry(&entries, id, project_root)?;
+    let entry = load_cache_entry(&cache_dir, &index_entry.filename, keyring)?;
+
+    Ok((index_entry.filename.clone(), entry))
+}
+
+pub fn show_cache_entry(
+    cache_dir_override: &Option<String>,
+    args: &CacheShowArgs,
+    project_root: Option<&str>,
+    osc52: bool,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let (filename, entry) = find_cache_entry(cache_dir_override, &args.id, project_root, keyring)?;
+
+    print_cache_preview(&filename, &entry, args.lines);
+
+    if let Some(output_file) = &args.output_file {
+        write_cache_to_file(&entry, Path::new(output_file))?;
+    }
+    if args.copy {
+        copy_cache_to_clipboard(&entry, osc52)?;
+    }
+    if args.delete {
+        delete_cache_entry(&cache_dir, &filename, keyring)?;
+        println!("Deleted cache entry {}", filename);
+    }
+
+    let acted = args.copy || args.delete || args.output_file.is_some();
+    if !acted && std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        prompt_cache_action(&cache_dir, &filename, &entry, osc52, keyring)?;
+    }
+
+    Ok(())
+}
+
+/// A source file as it appeared in a session or on disk.
+pub struct FileSnapshot {
+    pub hash: Option<String>,
+    pub text: String,
+}
+
+/// Splits an entry's content back into its source files using the manifest.
+pub fn entry_snapshot(filename: &str, entry: &CacheEntry) -> Result<BTreeMap<String, FileSnapshot>> {
+    entry.require_content().with_context(|| format!("Can't compare {}", filename))?;
+    if entry.files.is_empty() && entry.source_file_count > 0 {
+        return Err(anyhow::anyhow!(
+            "Cache entry {} has no file manifest (it was created by an older version)",
+            filename
+        ));
+    }
+
+    Ok(entry
+        .files
+        .iter()
+        .map(|file| {
+            let text = entry
+                .content
+                .get(file.offset..file.offset + file.length)
+                .unwrap_or_default()
+                .to_string();
+            (file.path.clone(), FileSnapshot { hash: file.hash.clone(), text })
+        })
+        .collect())
+}
+
+pub fn print_snapshot_diff(
+    old_label: &str,
+    old: &BTreeMap<String, FileSnapshot>,
+    new_label: &str,
+    new: &BTreeMap<String, FileSnapshot>,
+    stat_only: bool,
+) {
+    let added: Vec<&String> = new.keys().filter(|path| !old.contains_key(*path)).collect();
+    let removed: Vec<&String> = old.keys().filter(|path| !new.contains_key(*path)).collect();
+    let changed: Vec<&String> = old
+        .iter()
+        .filter_map(|(path, before)| {
+            let after = new.get(path)?;
+            let differs = match (&before.hash, &after.hash) {
+                (Some(a), Some(b)) if a != b => true,
+                _ => before.text != after.text,
+            };
+            differs.then_some(path)
+        })
+        .collect();
+
+    println!("Comparing {} with {}", old_label.bold(), new_label.bold());
+    for path in &added {
+        println!("  {} {}", "added:  ".green(), path);
+    }
+    for path in &removed {
+        println!("  {} {}", "removed:".red(), path);
+    }
+    for path in &changed {
+        println!("  {} {}", "changed:".yellow(), path);
+    }
+    println!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        added.len(),
+        removed.len(),
+        changed.len(),
+        old.len() - removed.len() - changed.len()
+    );
+
+    if stat_only {
+        return;
+    }
+
+    for path in changed {
+        let diff = TextDiff::from_lines(&old[path].text, &new[path].text);
+        let unified = diff
+            .unified_diff()
+            .context_radius(3)
+            .header(&format!("a/{} ({})", path, old_label), &format!("b/{} ({})", path, new_label))
+            .to_string();
+
+        println!();
+        for line in unified.lines() {
+            if line.starts_with("+++") || line.starts_with("---") {
+                println!("{}", line.bold());
+            } else if line.starts_with("@@") {
+                println!("{}", line.cyan());
+            } else if line.starts_with('+') {
+                println!("{}", line.green());
+            } else if line.starts_with('-') {
+                println!("{}", line.red());
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+pub fn diff_cache_entries(
+    cache_dir_override: &Option<String>,
+    args: &CacheDiffArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let new_id = args
+        .new
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Specify a second cache entry or --live"))?;
+
+    let (old_filename, old_entry) = find_cache_entry(cache_dir_override, &args.old, project_root, keyring)?;
+    let (new_filename, new_entry) = find_cache_entry(cache_dir_override, new_id, project_root, keyring)?;
+
+    print_snapshot_diff(
+        &old_filename,
+        &entry_snapshot(&old_filename, &old_entry)?,
+        &new_filename,
+        &entry_snapshot(&new_filename, &new_entry)?,
+        args.stat,
+    );
+
+    Ok(())
+}
+
+/// Sessions packed into a single file for moving them to another cache.
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    format: String,
+    version: u32,
+    exported_at: DateTime<Utc>,
+    sessions: Vec<BundledSession>,
+}
+
+/// A session with its content inline, independent of any blobs.
+#[derive(Serialize, Deserialize)]
+struct BundledSession {
+    filename: String,
+    entry: CacheEntry,
+}
+
+/// A file's piece of a session's content: the header written before it on
+/// its line (e.g. its name) and its text.
+struct FileSegment<'a> {
+    file: &'a ManifestFile,
+    header: &'a str,
+    text: &'a str,
+}
+
+/// Splits a session's content along its manifest. Anything before the first
+/// file's line, such as a directory tree, is left out.
+fn file_segments<'a>(filename: &str, entry: &'a CacheEntry) -> Result<Vec<FileSegment<'a>>> {
+    let mut segments = Vec::new();
+    let mut previous_end = 0;
+    for file in &entry.files {
+        let end = file.offset + file.length;
+        let text = entry.content.get(file.offset..end);
+        let line_start = entry.content.get(..file.offset).map(|before| before.rfind('\n').map_or(0, |i| i + 1));
+        let (Some(text), Some(line_start)) = (text, line_start) else {
+            return Err(anyhow::anyhow!("The manifest of {} doesn't match its content", filename));
+        };
+        segments.push(FileSegment {
+            file,
+            header: &entry.content[line_start.max(previous_end).min(file.offset)..file.offset],
+            text,
+        });
+        previous_end = end;
+    }
+    Ok(segments)
+}
+
+/// Resolves `.` and `..` in a path without touching the file system.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Combines sessions into a new session. Files that appear in more than one
+/// are included once, in the version from the newest session, at the place
+/// they first appear.
+pub fn merge_cache_entries(
+    cache_dir_override: &Option<String>,
+    args: &CacheMergeArgs,
+    project_root: Option<&str>,
+    retention: &CacheConfig,
+    osc52: bool,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = list_cache_entries(cache_dir_override, keyring)?;
+
+    let mut sources = Vec::new();
+    for id in &args.ids {
+        let filename = resolve_cache_entry(&entries, id, project_root)?.filename.clone();
+        if sources.iter().any(|(existing, _)| *existing == filename) {
+            continue;
+        }
+        let entry = load_cache_entry(&cache_dir, &filename, keyring)?;
+        entry.require_content()?;
+        if entry.files.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Cache entry {} has no file manifest (it was created by an older version) and cannot be merged",
+                filename
+            ));
+        }
+        sources.push((filename, entry));
+    }
+    if sources.len() < 2 {
+        return Err(anyhow::anyhow!("Give at least two different sessions to merge"));
+    }
+
+    // Pick each file's newest version, keeping the order files first appear in
+    let mut order: Vec<PathBuf> = Vec::new();
+    let mut chosen: HashMap<PathBuf, (DateTime<Utc>, FileSegment)> = HashMap::new();
+    let mut total_files = 0;
+    for (filename, entry) in &sources {
+        for segment in file_segments(filename, entry)? {
+            total_files += 1;
+            let path = normalize_path(&Path::new(&entry.working_dir).join(&segment.file.path));
+            match chosen.get(&path) {
+                Some((timestamp, _)) if *timestamp >= entry.timestamp => {}
+                Some(_) => {
+                    chosen.insert(path, (entry.timestamp, segment));
+                }
+                None => {
+                    order.push(path.clone());
+                    chosen.insert(path, (entry.timestamp, segment));
+                }
+            }
+        }
+    }
+
+    // Manifest paths are relative to the deepest directory holding every file
+    let mut root = normalize_path(Path::new(&sources[0].1.working_dir));
+    for path in &order {
+        while !path.starts_with(&root) && root.pop() {}
+    }
+
+    let mut content = String::new();
+    let mut files = Vec::new();
+    for path in &order {
+        let (_, segment) = &chosen[path];
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(segment.header);
+        files.push(ManifestFile {
+            path: path.strip_prefix(&root).unwrap_or(path).to_string_lossy().to_string(),
+            offset: content.len(),
+            length: segment.text.len(),
+            blob: None,
+            ..segment.file.clone()
+        });
+        content.push_str(segment.text);
+    }
+
+    let working_dir = root.to_string_lossy().to_string();
+    let mut options = sources[0].1.options.clone();
+    options.tree = false;
+    options.osc52 = false;
+    options.output_file = None;
+    options.token_counter = token_counter_id(&args.token_counter).to_string();
+    options.gemini_multiplier = args.gemini_multiplier;
+
+    let entry = CacheEntry {
+        timestamp: Utc::now(),
+        token_count: count_tokens(&content, &args.token_counter, args.gemini_multiplier),
+        token_counter: Some(options.token_counter.clone()),
+        file_size: content.len(),
+        source_file_count: files.len(),
+        args_used: String::new(),
+        options,
+        project_root: project_root_of(&working_dir),
+        working_dir,
+        files,
+        content_in_blobs: false,
+        labels: SessionLabels::default(),
+        metadata_only: false,
+        content,
+    };
+    let filename = add_session(&cache_dir, &entry, retention, keyring)?;
+
+    println!(
+        "Merged {} sessions into {}: {} files ({} duplicates left out), {} characters, {}{} tokens",
+        sources.len(),
+        filename,
+        entry.source_file_count,
+        total_files - entry.source_file_count,
+        entry.file_size,
+        token_prefix(&entry),
+        entry.token_count.to_string().cyan()
+    );
+
+    if let Some(path) = &args.output_file {
+        write_cache_to_file(&entry, Path::new(path))?;
+    }
+    if args.copy {
+        copy_cache_to_clipboard(&entry, osc52)?;
+    }
+
+    Ok(())
+}
+
+/// Usage figures printed by `cache stats`.
+#[derive(Serialize)]
+struct CacheStats {
+    sessions: usize,
+    first_session: Option<DateTime<Utc>>,
+    last_session: Option<DateTime<Utc>>,
+    total_tokens: usize,
+    average_tokens: usize,
+    disk_usage: DiskUsage,
+    /// The last `--days` days, oldest first, including days without sessions.
+    per_day: Vec<DayStats>,
+    directories: Vec<DirectoryStats>,
+    largest_sessions: Vec<LargestSession>,
+}
+
+#[derive(Serialize)]
+struct DiskUsage {
+    /// Output size of all sessions before compression and deduplication.
+    content_bytes: u64,
+    session_bytes: u64,
+    blob_bytes: u64,
+    total_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct DayStats {
+    date: NaiveDate,
+    sessions: usize,
+    tokens: usize,
+    average_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct DirectoryStats {
+    working_dir: String,
+    sessions: usize,
+    tokens: usize,
+}
+
+#[derive(Serialize)]
+struct LargestSession {
+    id: usize,
+    filename: String,
+    timestamp: DateTime<Utc>,
+    token_count: usize,
+    working_dir: String,
+}
+
+/// Summarizes the cache index: activity per day, busiest directories,
+/// largest sessions and disk usage.
+pub fn print_cache_stats(
+    cache_dir_override: &Option<String>,
+    args: &CacheStatsArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let entries = CacheIndex { entries: list_cache_entries(cache_dir_override, keyring)? }.for_project(project_root);
+
+    let total_tokens: usize = entries.iter().map(|e| e.token_count).sum();
+    let session_bytes: u64 = entries
+        .iter()
+        .map(|e| fs::metadata(cache_dir.join("sessions").join(&e.filename)).map_or(e.disk_size, |m| m.len()))
+        .sum();
+    // Blobs are shared between sessions, so each one used by these sessions counts once
+    let blob_sizes = blob_sizes(&cache_dir);
+    let blobs: HashSet<&str> = entries.iter().flat_map(|e| e.blobs.iter().map(String::as_str)).collect();
+    let blob_bytes: u64 = blobs.iter().filter_map(|hash| blob_sizes.get(*hash)).sum();
+
+    let today = Local::now().date_naive();
+    let per_day = (0..args.days as u64)
+        .rev()
+        .filter_map(|days_ago| today.checked_sub_days(chrono::Days::new(days_ago)))
+        .map(|date| {
+            let day: Vec<&CacheIndexEntry> = entries
+                .iter()
+                .filter(|e| e.timestamp.with_timezone(&Local).date_naive() == date)
+                .collect();
+            let tokens = day.iter().map(|e| e.token_count).sum();
+            DayStats {
+                date,
+                sessions: day.len(),
+                tokens,
+                average_tokens: tokens.checked_div(day.len()).unwrap_or(0),
+            }
+        })
+        .collect();
+
+    let mut directories: Vec<DirectoryStats> = Vec::new();
+    for entry in &entries {
+        match directories.iter_mut().find(|d| d.working_dir == entry.working_dir) {
+            Some(directory) => {
+                directory.sessions += 1;
+                directory.tokens += entry.token_count;
+            }
+            None => directories.push(DirectoryStats {
+                working_dir: entry.working_dir.clone(),
+                sessions: 1,
+                tokens: entry.token_count,
+            }),
+        }
+    }
+    directories.sort_by_key(|d| std::cmp::Reverse((d.sessions, d.tokens)));
+    directories.truncate(args.top);
+
+    let mut largest_sessions: Vec<LargestSession> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| LargestSession {
+            id: i + 1,
+            filename: e.filename.clone(),
+            timestamp: e.timestamp,
+            token_count: e.token_count,
+            working_dir: e.working_dir.clone(),
+        })
+        .collect();
+    largest_sessions.sort_by_key(|s| std::cmp::Reverse(s.token_count));
+    largest_sessions.truncate(args.top);
+
+    let stats = CacheStats {
+        sessions: entries.len(),
+        first_session: entries.iter().map(|e| e.timestamp).min(),
+        last_session: entries.iter().map(|e| e.timestamp).max(),
+        total_tokens,
+        average_tokens: total_tokens.checked_div(entries.len()).unwrap_or(0),
+        disk_usage: DiskUsage {
+            content_bytes: entries.iter().map(|e| e.file_size as u64).sum(),
+            session_bytes,
+            blob_bytes,
+            total_bytes: session_bytes + blob_bytes,
+        },
+        per_day,
+        directories,
+        largest_sessions,
+    };
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&stats).context("Failed to serialize cache stats")?;
+        return ignore_broken_pipe(writeln!(std::io::stdout().lock(), "{}", json))
+            .context("Failed to write to stdout");
+    }
+    ignore_broken_pipe(write_cache_stats(&mut std::io::stdout().lock(), &stats, args.days))
+        .context("Failed to write to stdout")
+}
+
+fn write_cache_stats(out: &mut impl Write, stats: &CacheStats, days: usize) -> std::io::Result<()> {
+    if stats.sessions == 0 {
+        writeln!(out, "No cached entries found.")?;
+        return Ok(());
+    }
+
+    let local_date = |t: Option<DateTime<Utc>>| t.map(|t| t.with_timezone(&Local).format("%d %b %Y").to_string());
+    writeln!(
+        out,
+        "Sessions: {} ({} to {})",
+        stats.sessions,
+        local_date(stats.first_session).unwrap_or_default(),
+        local_date(stats.last_session).unwrap_or_default()
+    )?;
+    writeln!(
+        out,
+        "Tokens: {} in total, {} per session on average",
+        stats.total_tokens.to_string().cyan(),
+        stats.average_tokens.to_string().cyan()
+    )?;
+    writeln!(
+        out,
+        "Disk usage: {} ({} of sessions, {} of blobs) for {} of output",
+        format_size(stats.disk_usage.total_bytes),
+        format_size(stats.disk_usage.session_bytes),
+        format_size(stats.disk_usage.blob_bytes),
+        format_size(stats.disk_usage.content_bytes)
+    )?;
+
+    writeln!(out, "\nLast {} days:", days)?;
+    let busiest = stats.per_day.iter().map(|d| d.sessions).max().unwrap_or(0).max(1);
+    for day in &stats.per_day {
+        let line = format!(
+            "  {}  {:>3} sessions  {:>9} tokens  {:>7} avg",
+            day.date.format("%a %d %b"),
+            day.sessions,
+            day.tokens,
+            day.average_tokens
+        );
+        if day.sessions == 0 {
+            writeln!(out, "{}", line.dimmed())?;
+        } else {
+            writeln!(out, "{}  {}", line, "■".repeat((day.sessions * 20).div_ceil(busiest)).cyan())?;
+        }
+    }
+
+    writeln!(out, "\nMost frequent directories:")?;
+    for directory in &stats.directories {
+        writeln!(
+            out,
+            "  {:>3} sessions  {:>9} tokens  {}",
+            directory.sessions,
+            directory.tokens,
+            display_path(&directory.working_dir)
+        )?;
+    }
+
+    writeln!(out, "\nLargest sessions:")?;
+    for session in &stats.largest_sessions {
+        writeln!(
+            out,
+            "  [{:02}] {:>9} tokens  {}  {}",
+            session.id,
+            session.token_count,
+            session.timestamp.with_timezone(&Local).format("%d %b %Y %H:%M"),
+            display_path(&session.working_dir)
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn export_cache_entries(
+    cache_dir_override: &Option<String>,
+    args: &CacheExportArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> Result<()> {
+    let mut sessions = Vec::new();
+    for id in &args.ids {
+        let (filename, entry) = find_cache_entry(cache_dir_override, id, project_root, keyring)?;
+        if sessions.iter().any(|s: &BundledSession| s.filename == filename) {
+            continue;
+        }
+        sessions.push(BundledSession { filename, entry });
+    }
+
+    let bundle = Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        exported_at: Utc::now(),
+        sessions,
+    };
+    let serialized = serde_json::to_vec(&bundle).context("Failed to serialize bundle")?;
+    let compressed = zstd::encode_all(&serialized[..], COMPRESSION_LEVEL).context("Failed to compress bundle")?;
+
+    let path = Path::new(&args.output);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    file.write_all(&compressed).context("Failed to write bundle")?;
+
+    println!(
+        "Exported {} sessions ({}) to {}",
+        bundle.sessions.len(),
+        format_size(compressed.len() as u64),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Adds the sessions in a bundle to the cache. Retention is not applied here, as
+/// imported sessions keep their original timestamps; the sessions the next
+/// cleanup would remove are reported instead.
+pub fn import_cache_bundle(
+    cache_dir_override: &Option<String>,
+    args: &CacheImportArgs,
+    retention: &CacheConfig,
+    keyring: &Keyring,
+) -> Result<()> {
+    let bytes = fs::read(&args.bundle).with_context(|| format!("Failed to read {}", args.bundle))?;
+    let json = zstd::decode_all(&bytes[..]).context("Not a cache bundle")?;
+    let bundle: Bundle = serde_json::from_slice(&json).context("Not a cache bundle")?;
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(anyhow::anyhow!("Not a cache bundle"));
+    }
+    if bundle.version > BUNDLE_VERSION {
+        return Err(anyhow::anyhow!(
+            "Bundle version {} is newer than this version of xhinobi supports",
+            bundle.version
+        ));
+    }
+
+    let cache_dir = get_cache_dir(cache_dir_override)?;
+    let sessions_dir = cache_dir.join("sessions");
+    let _lock = lock_cache(&cache_dir)?;
+
+    let mut imported = 0;
+    for BundledSession { filename, mut entry } in bundle.sessions {
+        entry.migrate();
+        entry.content_in_blobs = false;
+
+        // The same session may have been imported before, possibly under another file name
+        let index = read_cache_index(&cache_dir, keyring)?;
+        let duplicate = index
+            .entries
+            .iter()
+            .filter(|e| e.timestamp == entry.timestamp && e.file_size == entry.file_size)
+            .filter_map(|e| load_cache_entry(&cache_dir, &e.filename, keyring).ok())
+            .any(|existing| existing.content == entry.content);
+        if duplicate {
+            println!("Skipped {}: already in the cache", filename);
+            continue;
+        }
+
+        if let Some(name) = &entry.labels.name {
+            if index.entries.iter().any(|e| e.labels.name.as_ref() == Some(name)) {
+                eprintln!("Warning: The name '{}' is already used; importing {} without it", name, filename);
+                entry.labels.name = None;
+            }
+        }
+
+        let filename = if filename.ends_with(".cache") && !filename.contains('/') && !sessions_dir.join(&filename).exists() {
+            filename
+        } else {
+            new_session_filename(&sessions_dir, entry.timestamp)
+        };
+        let stored = write_session_file(&cache_dir, &sessions_dir.join(&filename), &entry, keyring)?;
+        update_cache_index(&cache_dir, &stored, &filename, keyring)?;
+
+        println!("Imported {} ({})", filename, format_entry_summary(&index_entry_for(&cache_dir, &stored, &filename)));
+        imported += 1;
+    }
+
+    println!("Imported {} sessions from {}", imported, args.bundle);
+
+    let plan = plan_cleanup(&cache_dir, &read_cache_index(&cache_dir, keyring)?, retention);
+    for eviction in &plan.evictions {
+        eprintln!(
+            "Warning: The next cleanup will remove {}: {}",
+            eviction.filename,
+            eviction.reason.dimmed()
+        );
+    }
+    if !plan.evictions.is_empty() {
+        eprintln!("Pin the sessions to keep with `xhinobi cache pin <ID>`");
+    }
+
+    Ok(())
+}
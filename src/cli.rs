@@ -1,4 +1,7 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{MAX_CACHE_AGE_DAYS, MAX_CACHE_SIZE_MB};
 
 #[derive(ValueEnum, Clone, Debug)]
 pub enum TokenCounter {
@@ -12,11 +15,38 @@ pub enum TokenCounter {
     GeminiApprox,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlankLines {
+    /// Remove every blank line.
+    Remove,
+    /// Drop lines emptied by removed comments and collapse runs of blank lines.
+    Collapse,
+    /// Only drop lines emptied by removed comments.
+    Keep,
+}
+
+/// What is kept in the cache after a run.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// The output together with its manifest and options.
+    #[default]
+    Full,
+    /// Only the manifest, options and token count, without the output itself.
+    Metadata,
+    /// Nothing.
+    Off,
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "xhinobi")]
 #[command(about = "A tool for aggregating text content from multiple files")]
 #[command(version = "1.0")]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Prepend the file name before the content
     #[arg(short = 'n', long = "prependFileName")]
     pub prepend_file_name: bool,
@@ -41,22 +71,63 @@ pub struct Args {
     #[arg(short = 'd', long = "decomment")]
     pub decomment: bool,
 
-    /// Copy most recent cached result to clipboard (no stdin needed)
-    #[arg(long = "cache")]
-    pub cache: bool,
+    /// Also remove comments inside fenced code blocks in Markdown files (with --decomment)
+    #[arg(long = "decomment-fences")]
+    pub decomment_fences: bool,
+
+    /// How to handle blank lines after decommenting (default: remove, collapse for Markdown)
+    #[arg(long = "blank-lines", value_enum)]
+    pub blank_lines: Option<BlankLines>,
 
-    /// Show interactive list of cached sessions
+    /// Copy the current project's most recent cached result to clipboard (no stdin needed), or the session
+    /// selected by its number in `cache ls` (3), recency (-2), timestamp prefix, file name or name
+    #[arg(long = "cache", value_name = "SELECTOR", num_args = 0..=1, allow_negative_numbers = true)]
+    pub cache: Option<Option<String>>,
+
+    /// With --cache, print the session to stdout instead of copying it
+    #[arg(long = "stdout", requires = "cache", conflicts_with = "output_file")]
+    pub stdout: bool,
+
+    /// Show interactive list of the current project's cached sessions (a plain list without a terminal)
     #[arg(long = "list-cache")]
     pub list_cache: bool,
 
+    /// Use the cached sessions of every project, not just the current one; also changes what session numbers refer to
+    #[arg(long = "all-projects", global = true)]
+    pub all_projects: bool,
+
     /// Clear all cached sessions
     #[arg(long = "clear-cache")]
     pub clear_cache: bool,
 
+    /// Don't save this run to the cache
+    #[arg(long = "no-cache")]
+    pub no_cache: bool,
+
+    /// What to save to the cache after this run
+    #[arg(long = "cache-mode", value_enum, env = "XHINOBI_CACHE_MODE")]
+    pub cache_mode: Option<CacheMode>,
+
     /// Override default cache directory
-    #[arg(long = "cache-dir")]
+    #[arg(long = "cache-dir", global = true)]
     pub cache_dir: Option<String>,
 
+    /// Override default config file (~/.config/xhinobi/config.toml)
+    #[arg(long = "config", global = true)]
+    pub config: Option<String>,
+
+    /// Keep at most this many cached sessions (overrides the config file)
+    #[arg(long = "max-cache-entries", global = true)]
+    pub max_cache_entries: Option<usize>,
+
+    /// Keep the cache under this many megabytes on disk (overrides the config file)
+    #[arg(long = "max-cache-size-mb", global = true, value_parser = clap::value_parser!(u64).range(..=MAX_CACHE_SIZE_MB))]
+    pub max_cache_size_mb: Option<u64>,
+
+    /// Drop cached sessions older than this many days (overrides the config file)
+    #[arg(long = "max-cache-age-days", global = true, value_parser = clap::value_parser!(i64).range(0..=MAX_CACHE_AGE_DAYS))]
+    pub max_cache_age_days: Option<i64>,
+
     /// Show the cache directory path
     #[arg(long = "show-cache-dir")]
     pub show_cache_dir: bool,
@@ -69,7 +140,306 @@ pub struct Args {
     #[arg(long = "gemini-multiplier", default_value = "1.18")]
     pub gemini_multiplier: f64,
 
-    /// Write output to a .txt file (will not overwrite existing files)
+    /// Write output, or the session selected with --cache, to a .txt file (will not overwrite existing files)
+    #[arg(long = "output-file")]
+    pub output_file: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect and manage cached sessions
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List cached sessions as a table or JSON, without prompting
+    Ls(CacheLsArgs),
+    /// Search cached sessions by content, working directory, date and tokens
+    Search(CacheSearchArgs),
+    /// Preview a cached session, then copy, save or delete it
+    Show(CacheShowArgs),
+    /// Re-read a cached session's files from disk with the same options
+    Rerun(CacheRerunArgs),
+    /// Show which files were added, removed or changed between two sessions
+    Diff(CacheDiffArgs),
+    /// Delete sessions by ID, by filter or picked from a list
+    Rm(CacheRmArgs),
+    /// Combine sessions into a new one, keeping each file once
+    Merge(CacheMergeArgs),
+    /// Summarize sessions, tokens and disk usage over time
+    Stats(CacheStatsArgs),
+    /// Remove sessions that fall outside the retention limits
+    Prune(CachePruneArgs),
+    /// Give a session a name that can be used in place of its ID
+    Name(CacheNameArgs),
+    /// Add tags to a session, or remove them
+    Tag(CacheTagArgs),
+    /// Protect a session from automatic cleanup
+    Pin(CachePinArgs),
+    /// Let automatic cleanup remove a pinned session again
+    Unpin(CachePinArgs),
+    /// Check the cache index against the session files and repair it
+    Doctor(CacheDoctorArgs),
+    /// Write sessions to a bundle file that can be imported into another cache
+    Export(CacheExportArgs),
+    /// Add the sessions in a bundle file to the cache
+    Import(CacheImportArgs),
+    /// Create a random key file for encrypting the cache
+    Keygen(CacheKeygenArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheLsArgs {
+    /// Print the sessions as a JSON array
+    #[arg(long = "json")]
+    pub json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheSearchArgs {
+    /// Text to look for in the cached content (case-insensitive)
+    pub query: Option<String>,
+
+    /// Treat the query as a regular expression
+    #[arg(short = 'r', long = "regex")]
+    pub regex: bool,
+
+    /// Only sessions whose working directory contains this text
+    #[arg(long = "dir")]
+    pub dir: Option<String>,
+
+    /// Only sessions with this tag
+    #[arg(long = "tag")]
+    pub tag: Option<String>,
+
+    /// Only sessions on or after this date (YYYY-MM-DD or e.g. 7d for 7 days ago)
+    #[arg(long = "since")]
+    pub since: Option<String>,
+
+    /// Only sessions on or before this date (YYYY-MM-DD or e.g. 7d for 7 days ago)
+    #[arg(long = "until")]
+    pub until: Option<String>,
+
+    /// Only sessions with at least this many tokens
+    #[arg(long = "min-tokens")]
+    pub min_tokens: Option<usize>,
+
+    /// Only sessions with at most this many tokens
+    #[arg(long = "max-tokens")]
+    pub max_tokens: Option<usize>,
+
+    /// Maximum number of sessions to show
+    #[arg(long = "limit", default_value = "20")]
+    pub limit: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheShowArgs {
+    /// Session to show: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub id: String,
+
+    /// Number of content lines to preview
+    #[arg(long = "lines", default_value = "20")]
+    pub lines: usize,
+
+    /// Copy the session to the clipboard without asking
+    #[arg(long = "copy")]
+    pub copy: bool,
+
+    /// Use OSC52 escape sequence for clipboard over SSH
+    #[arg(short = 'o', long = "osc52")]
+    pub osc52: bool,
+
+    /// Write the session to a .txt file without asking
     #[arg(long = "output-file")]
     pub output_file: Option<String>,
+
+    /// Delete the session without asking
+    #[arg(long = "delete")]
+    pub delete: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheRerunArgs {
+    /// Session to re-run: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub id: String,
+
+    /// Write the fresh output to a .txt file (will not overwrite existing files)
+    #[arg(long = "output-file")]
+    pub output_file: Option<String>,
+
+    /// Use OSC52 escape sequence for clipboard over SSH
+    #[arg(short = 'o', long = "osc52")]
+    pub osc52: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheDiffArgs {
+    /// Older session: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub old: String,
+
+    /// Newer session to compare against
+    #[arg(required_unless_present = "live", conflicts_with = "live", allow_negative_numbers = true)]
+    pub new: Option<String>,
+
+    /// Compare the session's files against their current contents on disk
+    #[arg(long = "live")]
+    pub live: bool,
+
+    /// Only list added, removed and changed files without their content diffs
+    #[arg(long = "stat")]
+    pub stat: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheRmArgs {
+    /// Sessions to delete: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true, conflicts_with_all = ["dir", "older_than", "tag", "interactive"])]
+    pub ids: Vec<String>,
+
+    /// Delete sessions whose working directory contains this text
+    #[arg(long = "dir")]
+    pub dir: Option<String>,
+
+    /// Delete sessions older than this (YYYY-MM-DD or e.g. 30d for 30 days ago)
+    #[arg(long = "older-than")]
+    pub older_than: Option<String>,
+
+    /// Delete sessions with this tag
+    #[arg(long = "tag")]
+    pub tag: Option<String>,
+
+    /// Also delete pinned sessions that match the filters
+    #[arg(long = "include-pinned")]
+    pub include_pinned: bool,
+
+    /// Pick the sessions to delete from a list, narrowed down by any filters
+    #[arg(short = 'i', long = "interactive")]
+    pub interactive: bool,
+
+    /// List the sessions that would be deleted without deleting them
+    #[arg(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Don't ask before deleting the sessions matched by filters
+    #[arg(short = 'y', long = "yes")]
+    pub yes: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheMergeArgs {
+    /// Sessions to merge: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(required = true, num_args = 2.., allow_negative_numbers = true)]
+    pub ids: Vec<String>,
+
+    /// Token counting strategy for the merged session
+    #[arg(long = "token-counter", value_enum, default_value = "estimate")]
+    pub token_counter: TokenCounter,
+
+    /// Multiplier used for gemini-approx
+    #[arg(long = "gemini-multiplier", default_value = "1.18")]
+    pub gemini_multiplier: f64,
+
+    /// Copy the merged session to the clipboard
+    #[arg(long = "copy")]
+    pub copy: bool,
+
+    /// Use OSC52 escape sequence for clipboard over SSH
+    #[arg(short = 'o', long = "osc52")]
+    pub osc52: bool,
+
+    /// Also write the merged session to a .txt file (will not overwrite existing files)
+    #[arg(long = "output-file")]
+    pub output_file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheStatsArgs {
+    /// Print the statistics as JSON
+    #[arg(long = "json")]
+    pub json: bool,
+
+    /// Number of recent days to break down per day
+    #[arg(long = "days", default_value = "14")]
+    pub days: usize,
+
+    /// Number of directories and sessions to list
+    #[arg(long = "top", default_value = "5")]
+    pub top: usize,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CachePruneArgs {
+    /// List the sessions that would be removed without removing them
+    #[arg(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheNameArgs {
+    /// Session: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub id: String,
+
+    /// New name; leave out to remove the current one
+    pub name: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheTagArgs {
+    /// Session: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub id: String,
+
+    /// Tags to add
+    #[arg(required = true)]
+    pub tags: Vec<String>,
+
+    /// Remove the tags instead of adding them
+    #[arg(long = "remove")]
+    pub remove: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CachePinArgs {
+    /// Session: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(allow_negative_numbers = true)]
+    pub id: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheDoctorArgs {
+    /// Only report problems without changing anything
+    #[arg(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheExportArgs {
+    /// Sessions to export: its number in `cache ls`, -N for the N-th newest, a timestamp prefix, its file name or name
+    #[arg(required = true, allow_negative_numbers = true)]
+    pub ids: Vec<String>,
+
+    /// Bundle file to create (will not overwrite existing files)
+    #[arg(short = 'o', long = "output")]
+    pub output: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheImportArgs {
+    /// Bundle file created by `cache export`
+    pub bundle: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheKeygenArgs {
+    /// Key file to create (will not overwrite existing files)
+    pub path: String,
 }
@@ -0,0 +1,188 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{BlankLines, CacheMode};
+
+const CONFIG_DIR_NAME: &str = "xhinobi";
+const CONFIG_FILE_NAME: &str = "config.toml";
+const DEFAULT_MAX_CACHE_ENTRIES: usize = 50;
+const DEFAULT_MAX_CACHE_SIZE_MB: u64 = 100;
+const DEFAULT_MAX_CACHE_AGE_DAYS: i64 = 90;
+/// Upper bounds for the retention limits, far beyond any useful value but
+/// small enough that dates and byte counts computed from them can't overflow.
+pub const MAX_CACHE_AGE_DAYS: i64 = 36_500;
+pub const MAX_CACHE_SIZE_MB: u64 = 1 << 30;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub decomment: DecommentConfig,
+    pub cache: CacheConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DecommentConfig {
+    /// Keep shebangs, pragmas and tool directives when removing comments.
+    pub keep_directives: bool,
+    /// Per-language regex patterns that replace the built-in keep list.
+    pub keep: HashMap<String, Vec<String>>,
+    /// Blank line handling after decommenting; defaults depend on the language.
+    pub blank_lines: Option<BlankLines>,
+}
+
+impl Default for DecommentConfig {
+    fn default() -> Self {
+        DecommentConfig {
+            keep_directives: true,
+            keep: HashMap::new(),
+            blank_lines: None,
+        }
+    }
+}
+
+/// How many cached sessions are kept, and for how long.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub mode: CacheMode,
+    pub max_entries: usize,
+    /// Limit on the compressed sessions and blobs on disk.
+    pub max_size_mb: u64,
+    pub max_age_days: i64,
+    /// Rules for sessions whose working directory is inside the given path.
+    pub projects: HashMap<String, ProjectCacheConfig>,
+    pub encryption: EncryptionConfig,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            mode: CacheMode::Full,
+            max_entries: DEFAULT_MAX_CACHE_ENTRIES,
+            max_size_mb: DEFAULT_MAX_CACHE_SIZE_MB,
+            max_age_days: DEFAULT_MAX_CACHE_AGE_DAYS,
+            projects: HashMap::new(),
+            encryption: EncryptionConfig::default(),
+        }
+    }
+}
+
+/// Retention for one project, applied on top of the global limits.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProjectCacheConfig {
+    /// Replaces the global cache mode for runs inside this project.
+    pub mode: Option<CacheMode>,
+    /// Sessions kept for this project; they still count towards the global limit.
+    pub max_entries: Option<usize>,
+    /// Replaces the global age limit for this project's sessions.
+    pub max_age_days: Option<i64>,
+}
+
+/// Encryption of cached sessions at rest.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    /// Key file to use instead of a passphrase, e.g. one made by `cache keygen`.
+    pub key_file: Option<String>,
+}
+
+impl CacheConfig {
+    /// Finds the rules of the most specific project containing `working_dir`.
+    pub fn project_for(&self, working_dir: &str) -> Option<(&str, &ProjectCacheConfig)> {
+        let working_dir = Path::new(working_dir);
+        self.projects
+            .iter()
+            .map(|(path, rules)| (path.as_str(), expand_home(path), rules))
+            .filter(|(_, root, _)| working_dir.starts_with(root))
+            .max_by_key(|(_, root, _)| root.components().count())
+            .map(|(path, _, rules)| (path, rules))
+    }
+
+    /// Rejects retention limits outside the supported range.
+    fn validate(&self) -> Result<()> {
+        let ages = std::iter::once(("max_age_days".to_string(), Some(self.max_age_days))).chain(
+            self.projects
+                .iter()
+                .map(|(path, rules)| (format!("projects.\"{}\".max_age_days", path), rules.max_age_days)),
+        );
+        for (key, days) in ages {
+            if let Some(days) = days.filter(|days| !(0..=MAX_CACHE_AGE_DAYS).contains(days)) {
+                return Err(anyhow::anyhow!(
+                    "cache.{} must be between 0 and {}, not {}",
+                    key,
+                    MAX_CACHE_AGE_DAYS,
+                    days
+                ));
+            }
+        }
+        if self.max_size_mb > MAX_CACHE_SIZE_MB {
+            return Err(anyhow::anyhow!(
+                "cache.max_size_mb must be at most {}, not {}",
+                MAX_CACHE_SIZE_MB,
+                self.max_size_mb
+            ));
+        }
+        Ok(())
+    }
+
+    /// The cache mode configured for runs in `working_dir`.
+    pub fn mode_for(&self, working_dir: &str) -> CacheMode {
+        self.project_for(working_dir)
+            .and_then(|(_, rules)| rules.mode)
+            .unwrap_or(self.mode)
+    }
+}
+
+/// Replaces a leading `~` with the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            PathBuf::from(format!("{}{}", home, rest))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+pub fn get_config_path(override_path: &Option<String>) -> Result<PathBuf> {
+    if let Some(custom_path) = override_path {
+        return Ok(PathBuf::from(custom_path));
+    }
+
+    // Use XDG config directory or fallback to ~/.config
+    let config_dir = if let Ok(xdg_dir) = env::var("XDG_CONFIG_HOME") {
+        PathBuf::from(xdg_dir)
+    } else {
+        let home = env::var("HOME").context("Could not find HOME directory")?;
+        PathBuf::from(home).join(".config")
+    };
+
+    Ok(config_dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+}
+
+pub fn load_config(override_path: &Option<String>) -> Result<Config> {
+    let config_path = get_config_path(override_path)?;
+
+    if !config_path.exists() {
+        if override_path.is_some() {
+            return Err(anyhow::anyhow!("Config file {} not found", config_path.display()));
+        }
+        return Ok(Config::default());
+    }
+
+    let config_content = fs::read_to_string(&config_path).context("Failed to read config file")?;
+    let config: Config = toml::from_str(&config_content)
+        .with_context(|| format!("Failed to parse config file {}", config_path.display()))?;
+    config
+        .cache
+        .validate()
+        .with_context(|| format!("Invalid config file {}", config_path.display()))?;
+
+    Ok(config)
+}
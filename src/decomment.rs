@@ -1,23 +1,229 @@
 use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::HashMap;
 use std::path::Path;
 use tree_sitter::{Language, Parser, Range};
 
-pub fn clean_code(content: &str, language: Language) -> Result<String> {
+use crate::cli::BlankLines;
+use crate::config::DecommentConfig;
+use crate::embedded::{self, HostLanguage, RegionLanguage};
+
+/// A tree-sitter grammar together with the name used to look up its keep rules.
+#[derive(Clone)]
+pub struct Grammar {
+    pub name: &'static str,
+    pub language: Language,
+}
+
+/// Comments that carry meaning for tools or the runtime and are kept by default.
+const UNIVERSAL_KEEP_PATTERNS: &[&str] = &[r"SPDX-License-Identifier", r"@license\b", r"@preserve\b"];
+
+const KEEP_RULE_LANGUAGES: &[&str] = &[
+    "typescript", "tsx", "javascript", "json", "python", "rust", "go", "bash", "php", "lua", "dart",
+    "css", "html", "markdown",
+];
+
+/// Maps a `[decomment.keep]` key, a language name or file extension, to the
+/// name its rules are looked up by.
+fn keep_rule_language(key: &str) -> Option<&'static str> {
+    let key = key.to_ascii_lowercase();
+    language_for_name(&key)
+        .map(|grammar| grammar.name)
+        .or_else(|| embedded::host_language_for_name(&key).map(|host| host.name()))
+        .or_else(|| (key == "css").then_some("css"))
+}
+
+fn default_keep_patterns(name: &str) -> &'static [&'static str] {
+    match name {
+        "python" => &[
+            r"^#!",
+            r"^#.*coding[:=]",
+            r"^#\s*type:",
+            r"^#\s*noqa",
+            r"^#\s*pragma:",
+            r"^#\s*fmt:\s*(on|off|skip)",
+        ],
+        "bash" => &[r"^#!", r"^#\s*shellcheck\s"],
+        "typescript" | "tsx" | "javascript" => &[
+            r"^//\s*@ts-",
+            r"^/// <reference",
+            r"^/(/|\*)\s*eslint",
+            r"^/(/|\*)\s*prettier-ignore",
+            r"^/(/|\*)\s*istanbul ignore",
+            r"^/\*\*?\s*@(jsx|jsxImportSource|jsxFrag|jsxRuntime)\b",
+            r"^//\s*@flow",
+            r"^/\*!",
+        ],
+        "go" => &[r"^//go:", r"^// \+build", r"^//line ", r"^//export ", r"^//\s*#cgo", r"^//\s*nolint"],
+        "rust" => &[r"^//\s*rustfmt::skip"],
+        "php" => &[r"@(phpstan|psalm)-", r"phpcs:"],
+        "dart" => &[r"^//\s*ignore(_for_file)?:", r"^//\s*@dart\s*="],
+        "lua" => &[r"^#!", r"^---\s*@diagnostic"],
+        "css" => &[r"^/\*!"],
+        "html" => &[r"^<!--\s*\[if", r"^<!--\s*(prettier-ignore|eslint-)"],
+        "markdown" => &[r"^<!--\s*(markdownlint-|prettier-ignore)"],
+        _ => &[],
+    }
+}
+
+/// Per-language patterns for comments that survive decommenting.
+pub struct KeepRules {
+    rules: HashMap<String, Vec<Regex>>,
+    enabled: bool,
+}
+
+impl KeepRules {
+    /// Builds the rules from the built-in defaults, replacing the list of any
+    /// language that has its own entry under `[decomment.keep]` in the config.
+    pub fn new(config: &DecommentConfig) -> Result<Self> {
+        let compile = |patterns: &mut dyn Iterator<Item = &str>| -> Result<Vec<Regex>> {
+            patterns
+                .map(|p| Regex::new(p).with_context(|| format!("Invalid keep pattern '{}'", p)))
+                .collect()
+        };
+
+        let mut rules = HashMap::new();
+        for name in KEEP_RULE_LANGUAGES {
+            let mut patterns = UNIVERSAL_KEEP_PATTERNS
+                .iter()
+                .chain(default_keep_patterns(name))
+                .copied();
+            rules.insert(name.to_string(), compile(&mut patterns)?);
+        }
+        let mut configured: HashMap<&str, &str> = HashMap::new();
+        for (key, patterns) in &config.keep {
+            let name = keep_rule_language(key).with_context(|| {
+                format!(
+                    "Unknown language '{}' in [decomment.keep], expected one of: {}",
+                    key,
+                    KEEP_RULE_LANGUAGES.join(", ")
+                )
+            })?;
+            if let Some(other) = configured.insert(name, key) {
+                return Err(anyhow::anyhow!("[decomment.keep] has both '{}' and '{}' for {}", other, key, name));
+            }
+            let mut patterns = patterns.iter().map(String::as_str);
+            rules.insert(name.to_string(), compile(&mut patterns)?);
+        }
+
+        Ok(KeepRules {
+            rules,
+            enabled: config.keep_directives,
+        })
+    }
+
+    fn keeps(&self, name: &str, comment: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let comment = comment.trim_start();
+        self.rules
+            .get(name)
+            .is_some_and(|patterns| patterns.iter().any(|re| re.is_match(comment)))
+    }
+
+    fn retain_removable(&self, name: &str, content: &str, comments: &mut Vec<Range>) {
+        comments.retain(|r| !self.keeps(name, &content[r.start_byte..r.end_byte]));
+    }
+}
+
+/// Settings shared by every file decommented in a run.
+pub struct DecommentOptions {
+    pub keep: KeepRules,
+    /// Overrides the per-language blank line handling when set.
+    pub blank_lines: Option<BlankLines>,
+    pub include_fences: bool,
+}
+
+/// How lines are tidied up once comments have been cut out.
+#[derive(Clone, Copy)]
+struct Layout {
+    blank_lines: BlankLines,
+    trim_trailing: bool,
+}
+
+impl Layout {
+    fn for_language(name: &str, blank_lines: Option<BlankLines>) -> Self {
+        // Blank lines separate paragraphs and trailing spaces are hard line breaks in Markdown.
+        let whitespace_sensitive = name == "markdown";
+        Layout {
+            blank_lines: blank_lines.unwrap_or(if whitespace_sensitive {
+                BlankLines::Collapse
+            } else {
+                BlankLines::Remove
+            }),
+            trim_trailing: !whitespace_sensitive,
+        }
+    }
+}
+
+/// Comment nodes to remove and string nodes whose text must be left untouched.
+#[derive(Default)]
+struct SyntaxRanges {
+    comments: Vec<Range>,
+    strings: Vec<Range>,
+}
+
+pub fn clean_code(content: &str, grammar: Grammar, options: &DecommentOptions) -> Result<String> {
+    let mut ranges = syntax_ranges(content, grammar.language, None)?;
+    options.keep.retain_removable(grammar.name, content, &mut ranges.comments);
+    let layout = Layout::for_language(grammar.name, options.blank_lines);
+    Ok(remove_ranges(content, ranges.comments, &ranges.strings, &[], layout))
+}
+
+/// Removes comments from a host document and from the code embedded in it,
+/// parsing each embedded section with its own grammar.
+pub fn clean_embedded(content: &str, host: HostLanguage, options: &DecommentOptions) -> Result<String> {
+    let scan = embedded::scan(content, host, options.include_fences);
+    let mut comments = scan.comments;
+    let mut protected = scan.verbatim;
+    let mut code = Vec::new();
+    options.keep.retain_removable(host.name(), content, &mut comments);
+
+    for region in scan.regions {
+        code.push(region.range);
+        let mut ranges = match &region.language {
+            RegionLanguage::Code(grammar) => {
+                syntax_ranges(content, grammar.language.clone(), Some(region.range))?
+            }
+            RegionLanguage::Css => SyntaxRanges {
+                comments: embedded::css_comment_ranges(content, &region.range),
+                strings: Vec::new(),
+            },
+        };
+        options.keep.retain_removable(region.language.name(), content, &mut ranges.comments);
+        comments.extend(ranges.comments);
+        protected.extend(ranges.strings);
+    }
+
+    let layout = Layout::for_language(host.name(), options.blank_lines);
+    Ok(remove_ranges(content, comments, &protected, &code, layout))
+}
+
+fn syntax_ranges(content: &str, language: Language, included: Option<Range>) -> Result<SyntaxRanges> {
     let mut parser = Parser::new();
     parser
         .set_language(&language)
         .context("Error loading language grammar")?;
+    if let Some(range) = included {
+        parser
+            .set_included_ranges(&[range])
+            .context("Invalid embedded code range")?;
+    }
     let tree = parser
         .parse(content, None)
         .context("Failed to parse the code")?;
 
-    let mut comments_to_remove: Vec<Range> = Vec::new();
+    let mut ranges = SyntaxRanges::default();
     let mut cursor = tree.root_node().walk();
 
     loop {
         let node = cursor.node();
-        if node.kind().contains("comment") {
-            comments_to_remove.push(node.range());
+        let kind = node.kind();
+        if kind.contains("comment") {
+            ranges.comments.push(node.range());
+        } else if kind.contains("string") || kind.contains("heredoc") || kind.contains("nowdoc") {
+            ranges.strings.push(node.range());
         }
 
         if cursor.goto_first_child() {
@@ -39,6 +245,18 @@ pub fn clean_code(content: &str, language: Language) -> Result<String> {
         }
     }
 
+    Ok(ranges)
+}
+
+/// Cuts the comments out of `content`. Lines inside `code` are tidied like
+/// source code even when the surrounding document keeps trailing whitespace.
+fn remove_ranges(
+    content: &str,
+    mut comments_to_remove: Vec<Range>,
+    protected: &[Range],
+    code: &[Range],
+    layout: Layout,
+) -> String {
     comments_to_remove.sort_by_key(|r| r.start_byte);
 
     let mut merged_ranges: Vec<Range> = Vec::new();
@@ -57,37 +275,137 @@ pub fn clean_code(content: &str, language: Language) -> Result<String> {
 
     let mut content_without_comments = String::new();
     let mut current_byte_pos = 0;
+    // Positions in the output where a comment used to be
+    let mut cuts = Vec::new();
 
-    for range in merged_ranges {
+    for range in &merged_ranges {
         content_without_comments.push_str(&content[current_byte_pos..range.start_byte]);
+        cuts.push(content_without_comments.len());
         current_byte_pos = range.end_byte;
     }
     content_without_comments.push_str(&content[current_byte_pos..]);
 
-    let final_cleaned_content: String = content_without_comments
-        .lines()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| line.trim_end())
-        .collect::<Vec<&str>>()
-        .join("\n");
+    // Translate string literal and code ranges into positions in the output.
+    // Only the last cut starting before `pos` can overlap it, as cuts are sorted and disjoint.
+    let removed_before: Vec<usize> = merged_ranges
+        .iter()
+        .scan(0, |total, r| {
+            *total += r.end_byte - r.start_byte;
+            Some(*total)
+        })
+        .collect();
+    let to_output = |pos: usize| match merged_ranges.partition_point(|r| r.start_byte < pos) {
+        0 => pos,
+        i => pos - (removed_before[i - 1] - merged_ranges[i - 1].end_byte.saturating_sub(pos)),
+    };
+    let to_output_ranges = |ranges: &[Range]| {
+        let mut ranges: Vec<(usize, usize)> = ranges
+            .iter()
+            .map(|r| (to_output(r.start_byte), to_output(r.end_byte)))
+            .collect();
+        ranges.sort_unstable();
+        ranges
+    };
 
-    Ok(final_cleaned_content)
+    tidy_lines(
+        &content_without_comments,
+        &cuts,
+        Coverage::new(to_output_ranges(protected)),
+        Coverage::new(to_output_ranges(code)),
+        layout,
+    )
 }
 
-pub fn get_language(file_path: &Path) -> Option<Language> {
-    let extension = file_path.extension()?.to_str()?;
-    match extension {
-        "ts" => Some(tree_sitter_typescript::language_typescript()),
-        "tsx" => Some(tree_sitter_typescript::language_tsx()),
-        "js" | "jsx" | "mjs" => Some(tree_sitter_javascript::language()),
-        "json" => Some(tree_sitter_json::language()),
-        "py" => Some(tree_sitter_python::language()),
-        "rs" => Some(tree_sitter_rust::language()),
-        "go" => Some(tree_sitter_go::language()),
-        "sh" | "bash" => Some(tree_sitter_bash::language()),
-        "php" => Some(tree_sitter_php::language_php()),
-        "lua" => Some(tree_sitter_lua::language()),
-        "dart" => Some(tree_sitter_dart::language()),
-        _ => None,
+/// Answers whether positions fall inside any of a set of possibly overlapping
+/// ranges, for positions asked in increasing order.
+struct Coverage {
+    ranges: Vec<(usize, usize)>,
+    next: usize,
+    covered_until: usize,
+}
+
+impl Coverage {
+    /// `ranges` must be sorted by their start.
+    fn new(ranges: Vec<(usize, usize)>) -> Self {
+        Coverage { ranges, next: 0, covered_until: 0 }
     }
+
+    fn contains(&mut self, pos: usize) -> bool {
+        while let Some(&(_, end)) = self.ranges.get(self.next).filter(|&&(start, _)| start <= pos) {
+            self.covered_until = self.covered_until.max(end);
+            self.next += 1;
+        }
+        pos < self.covered_until
+    }
+}
+
+/// Drops blank lines and trailing whitespace according to `layout`, leaving
+/// lines that belong to a string literal exactly as they were.
+fn tidy_lines(
+    content: &str,
+    cuts: &[usize],
+    mut protected: Coverage,
+    mut code: Coverage,
+    layout: Layout,
+) -> String {
+    let mut kept: Vec<&str> = Vec::new();
+    let mut previous_blank = false;
+    let mut line_start = 0;
+    let mut cuts = cuts.iter().copied().peekable();
+
+    for line in content.split('\n') {
+        let line_end = line_start + line.len();
+        let starts_in_string = protected.contains(line_start);
+        let in_code = code.contains(line_start);
+        let ends_in_string = protected.contains(line_end);
+
+        let text = if (layout.trim_trailing || in_code) && !ends_in_string {
+            line.trim_end()
+        } else {
+            line
+        };
+
+        let blank = line.trim().is_empty() && !starts_in_string;
+        while cuts.next_if(|&c| c < line_start).is_some() {}
+        let emptied = blank && cuts.peek().is_some_and(|&c| c <= line_end);
+        let drop = blank
+            && match layout.blank_lines {
+                BlankLines::Remove => true,
+                BlankLines::Collapse => emptied || previous_blank,
+                BlankLines::Keep => emptied,
+            };
+
+        if !drop {
+            kept.push(text);
+            previous_blank = blank;
+        }
+        line_start = line_end + 1;
+    }
+
+    kept.join("\n")
+}
+
+pub fn get_language(file_path: &Path) -> Option<Grammar> {
+    let extension = file_path.extension()?.to_str()?;
+    language_for_name(extension)
+}
+
+/// Resolves a file extension or a language name, as used in `lang` attributes
+/// and Markdown fence info strings.
+pub fn language_for_name(name: &str) -> Option<Grammar> {
+    let (name, language) = match name.to_ascii_lowercase().as_str() {
+        "ts" | "typescript" => ("typescript", tree_sitter_typescript::language_typescript()),
+        "tsx" => ("tsx", tree_sitter_typescript::language_tsx()),
+        "js" | "jsx" | "mjs" | "javascript" => ("javascript", tree_sitter_javascript::language()),
+        "json" => ("json", tree_sitter_json::language()),
+        "py" | "python" => ("python", tree_sitter_python::language()),
+        "rs" | "rust" => ("rust", tree_sitter_rust::language()),
+        "go" | "golang" => ("go", tree_sitter_go::language()),
+        "sh" | "bash" | "shell" => ("bash", tree_sitter_bash::language()),
+        "php" => ("php", tree_sitter_php::language_php()),
+        "lua" => ("lua", tree_sitter_lua::language()),
+        "dart" => ("dart", tree_sitter_dart::language()),
+        _ => return None,
+    };
+    Some(Grammar { name, language })
 }
\ No newline at end of file
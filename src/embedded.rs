@@ -0,0 +1,393 @@
+use std::path::Path;
+use tree_sitter::{Point, Range};
+
+use crate::decomment::{language_for_name, Grammar};
+
+/// Documents that host code written in other languages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostLanguage {
+    /// HTML and single-file components (Vue, Svelte).
+    Html,
+    Markdown,
+}
+
+impl HostLanguage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HostLanguage::Html => "html",
+            HostLanguage::Markdown => "markdown",
+        }
+    }
+}
+
+/// The language of a section embedded in a host document.
+#[derive(Clone)]
+pub enum RegionLanguage {
+    Code(Grammar),
+    Css,
+}
+
+impl RegionLanguage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionLanguage::Code(grammar) => grammar.name,
+            RegionLanguage::Css => "css",
+        }
+    }
+}
+
+/// A section of a host document that is written in another language.
+#[derive(Clone)]
+pub struct Region {
+    pub language: RegionLanguage,
+    pub range: Range,
+}
+
+/// Comments and embedded sections found while scanning a host document.
+#[derive(Default)]
+pub struct Scan {
+    pub comments: Vec<Range>,
+    pub regions: Vec<Region>,
+    /// Code blocks that are passed through untouched.
+    pub verbatim: Vec<Range>,
+}
+
+pub fn get_host_language(file_path: &Path) -> Option<HostLanguage> {
+    host_language_for_name(file_path.extension()?.to_str()?)
+}
+
+/// Resolves a file extension or a host language name.
+pub fn host_language_for_name(name: &str) -> Option<HostLanguage> {
+    match name {
+        "html" | "htm" | "xhtml" | "vue" | "svelte" => Some(HostLanguage::Html),
+        "md" | "markdown" | "mdx" => Some(HostLanguage::Markdown),
+        _ => None,
+    }
+}
+
+pub fn scan(content: &str, host: HostLanguage, include_fences: bool) -> Scan {
+    let mut result = Scan::default();
+    match host {
+        HostLanguage::Html => scan_html(content, &mut result),
+        HostLanguage::Markdown => scan_markdown(content, include_fences, &mut result),
+    }
+    result
+}
+
+/// Finds `<!-- -->` comments and the bodies of `<script>` and `<style>` elements.
+fn scan_html(content: &str, result: &mut Scan) {
+    let bytes = content.as_bytes();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos] != b'<' {
+            pos += 1;
+            continue;
+        }
+
+        if bytes[pos..].starts_with(b"<!--") {
+            let end = find(bytes, pos + 4, b"-->").map_or(bytes.len(), |i| i + 3);
+            result.comments.push(make_range(content, pos, end));
+            pos = end;
+            continue;
+        }
+
+        let tag = if starts_with_tag(bytes, pos, b"script") {
+            "script"
+        } else if starts_with_tag(bytes, pos, b"style") {
+            "style"
+        } else {
+            pos += 1;
+            continue;
+        };
+
+        let Some(open_end) = find_tag_end(bytes, pos + 1 + tag.len()) else {
+            break;
+        };
+        let attributes = &content[pos + 1 + tag.len()..open_end];
+        let body_start = open_end + 1;
+        let closing = format!("</{}", tag);
+        let body_end = find_ignore_case(bytes, body_start, closing.as_bytes()).unwrap_or(bytes.len());
+
+        let language = if tag == "script" {
+            script_language(attributes)
+        } else {
+            Some(RegionLanguage::Css)
+        };
+        if let Some(language) = language {
+            if body_end > body_start {
+                result.regions.push(Region {
+                    language,
+                    range: make_range(content, body_start, body_end),
+                });
+            }
+        }
+        pos = body_end;
+    }
+}
+
+struct OpenFence {
+    marker: u8,
+    length: usize,
+    language: Option<Grammar>,
+    body_start: usize,
+}
+
+/// Finds `<!-- -->` comments outside code blocks and, when requested, the
+/// bodies of fenced code blocks whose info string names a known language.
+fn scan_markdown(content: &str, include_fences: bool, result: &mut Scan) {
+    let mut offset = 0;
+    let mut fence: Option<OpenFence> = None;
+    let mut prose_start = 0;
+
+    for line in content.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let trimmed = &line[indent..];
+        let marker = trimmed.as_bytes().first().copied();
+        let run = match marker {
+            Some(b'`') | Some(b'~') if indent < 4 => {
+                trimmed.bytes().take_while(|&b| Some(b) == marker).count()
+            }
+            _ => 0,
+        };
+
+        match &fence {
+            None if run >= 3 => {
+                scan_markdown_comments(content, prose_start, line_start, result);
+                let info = trimmed[run..].trim();
+                let name = info.split(|c: char| c.is_whitespace() || c == '{').next().unwrap_or("");
+                fence = Some(OpenFence {
+                    marker: marker.unwrap_or(b'`'),
+                    length: run,
+                    language: language_for_name(name),
+                    body_start: offset,
+                });
+            }
+            Some(open)
+                if run >= open.length
+                    && marker == Some(open.marker)
+                    && trimmed[run..].trim().is_empty() =>
+            {
+                if line_start > open.body_start {
+                    let range = make_range(content, open.body_start, line_start);
+                    match (include_fences, &open.language) {
+                        (true, Some(language)) => result.regions.push(Region {
+                            language: RegionLanguage::Code(language.clone()),
+                            range,
+                        }),
+                        _ => result.verbatim.push(range),
+                    }
+                }
+                fence = None;
+                prose_start = offset;
+            }
+            _ => {}
+        }
+    }
+
+    if fence.is_none() {
+        scan_markdown_comments(content, prose_start, content.len(), result);
+    }
+}
+
+/// Finds complete `<!-- -->` comments in Markdown prose, skipping code spans.
+/// A comment that starts a line (an HTML block) may run over blank lines;
+/// one inside a paragraph has to end within it. An unclosed `<!--` is text.
+fn scan_markdown_comments(content: &str, start: usize, end: usize, result: &mut Scan) {
+    let bytes = &content.as_bytes()[..end];
+    let mut pos = start;
+    while pos < end {
+        match bytes[pos] {
+            b'`' => {
+                let run = bytes[pos..].iter().take_while(|&&b| b == b'`').count();
+                pos = code_span_end(bytes, pos + run, run).unwrap_or(pos + run);
+            }
+            b'<' if bytes[pos..].starts_with(b"<!--") => {
+                let line_start = bytes[..pos].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                let starts_block = pos - line_start <= 3 && bytes[line_start..pos].iter().all(|&b| b == b' ');
+                let limit = if starts_block { end } else { paragraph_end(bytes, pos) };
+                match find(&bytes[..limit], pos + 4, b"-->") {
+                    Some(i) => {
+                        result.comments.push(make_range(content, pos, i + 3));
+                        pos = i + 3;
+                    }
+                    None => pos += 4,
+                }
+            }
+            _ => pos += 1,
+        }
+    }
+}
+
+/// Where a code span opened by `run` backticks ends, if it is closed by a run
+/// of the same length within the paragraph.
+fn code_span_end(bytes: &[u8], from: usize, run: usize) -> Option<usize> {
+    let limit = paragraph_end(bytes, from);
+    let mut pos = from;
+    while pos < limit {
+        if bytes[pos] != b'`' {
+            pos += 1;
+            continue;
+        }
+        let closing = bytes[pos..limit].iter().take_while(|&&b| b == b'`').count();
+        if closing == run {
+            return Some(pos + run);
+        }
+        pos += closing;
+    }
+    None
+}
+
+/// The start of the next blank line after `from`, or the end of `bytes`.
+fn paragraph_end(bytes: &[u8], from: usize) -> usize {
+    let mut pos = from;
+    while let Some(newline) = find(bytes, pos, b"\n") {
+        let next = newline + 1;
+        let line_end = find(bytes, next, b"\n").unwrap_or(bytes.len());
+        if bytes[next..line_end].iter().all(|b| b.is_ascii_whitespace()) {
+            return next;
+        }
+        pos = next;
+    }
+    bytes.len()
+}
+
+/// Finds `/* */` comments in a CSS body, skipping over string literals.
+pub fn css_comment_ranges(content: &str, region: &Range) -> Vec<Range> {
+    let bytes = content.as_bytes();
+    let mut ranges = Vec::new();
+    let mut pos = region.start_byte;
+
+    while pos < region.end_byte {
+        match bytes[pos] {
+            quote @ (b'"' | b'\'') => {
+                pos += 1;
+                while pos < region.end_byte && bytes[pos] != quote && bytes[pos] != b'\n' {
+                    if bytes[pos] == b'\\' {
+                        pos += 1;
+                    }
+                    pos += 1;
+                }
+                pos += 1;
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                let end = find(&bytes[..region.end_byte], pos + 2, b"*/")
+                    .map_or(region.end_byte, |i| i + 2);
+                ranges.push(make_range(content, pos, end));
+                pos = end;
+            }
+            _ => pos += 1,
+        }
+    }
+
+    ranges
+}
+
+fn script_language(attributes: &str) -> Option<RegionLanguage> {
+    let lang = attribute_value(attributes, "lang");
+    let script_type = attribute_value(attributes, "type");
+
+    if let Some(lang) = lang {
+        return language_for_name(&lang).map(RegionLanguage::Code);
+    }
+
+    match script_type.as_deref() {
+        None | Some("") | Some("module") | Some("text/javascript") | Some("application/javascript") => {
+            language_for_name("js").map(RegionLanguage::Code)
+        }
+        Some("text/typescript") | Some("application/typescript") => {
+            language_for_name("ts").map(RegionLanguage::Code)
+        }
+        Some(other) if other.ends_with("json") => language_for_name("json").map(RegionLanguage::Code),
+        Some(_) => None,
+    }
+}
+
+fn attribute_value(attributes: &str, name: &str) -> Option<String> {
+    let lower = attributes.to_ascii_lowercase();
+    let mut search_from = 0;
+
+    while let Some(found) = lower[search_from..].find(name) {
+        let start = search_from + found;
+        search_from = start + name.len();
+
+        let preceded_by_space = lower[..start].ends_with(|c: char| c.is_whitespace());
+        let rest = lower[search_from..].trim_start();
+        if !preceded_by_space || !rest.starts_with('=') {
+            continue;
+        }
+
+        let value = rest[1..].trim_start();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value[1..].split(quote).next().unwrap_or(""),
+            _ => value.split(|c: char| c.is_whitespace() || c == '>').next().unwrap_or(""),
+        };
+        return Some(value.to_string());
+    }
+
+    None
+}
+
+fn starts_with_tag(bytes: &[u8], pos: usize, name: &[u8]) -> bool {
+    let after = pos + 1 + name.len();
+    bytes.len() > after
+        && bytes[pos + 1..after].eq_ignore_ascii_case(name)
+        && (bytes[after].is_ascii_whitespace() || bytes[after] == b'>' || bytes[after] == b'/')
+}
+
+/// Returns the position of the `>` closing an opening tag, skipping quoted attribute values.
+fn find_tag_end(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    let mut quote: Option<u8> = None;
+    while pos < bytes.len() {
+        match (quote, bytes[pos]) {
+            (Some(q), b) if b == q => quote = None,
+            (None, b'"' | b'\'') => quote = Some(bytes[pos]),
+            (None, b'>') => return Some(pos),
+            _ => {}
+        }
+        pos += 1;
+    }
+    None
+}
+
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    if from >= bytes.len() {
+        return None;
+    }
+    bytes[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| from + i)
+}
+
+fn find_ignore_case(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    if from >= bytes.len() {
+        return None;
+    }
+    bytes[from..]
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+        .map(|i| from + i)
+}
+
+pub fn make_range(content: &str, start_byte: usize, end_byte: usize) -> Range {
+    Range {
+        start_byte,
+        end_byte,
+        start_point: point_at(content, start_byte),
+        end_point: point_at(content, end_byte),
+    }
+}
+
+fn point_at(content: &str, byte: usize) -> Point {
+    let before = &content.as_bytes()[..byte];
+    let row = before.iter().filter(|&&b| b == b'\n').count();
+    let line_start = before.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    Point {
+        row,
+        column: byte - line_start,
+    }
+}
@@ -0,0 +1,338 @@
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::{expand_home, EncryptionConfig};
+
+/// Every encrypted cache file starts with these bytes.
+const MAGIC: &[u8; 4] = b"XHNE";
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN + NONCE_LEN;
+const KEY_FILE_BYTES: usize = 32;
+const SALT_FILE_NAME: &str = "encryption.salt";
+/// Holds `KEY_CHECK` encrypted with the cache's passphrase key, so that a
+/// mistyped passphrase is caught before anything is written with it.
+const KEY_CHECK_FILE_NAME: &str = "encryption.check";
+const KEY_CHECK: &[u8] = b"xhinobi cache key check";
+pub const PASSPHRASE_ENV: &str = "XHINOBI_CACHE_PASSPHRASE";
+
+/// Where the key of an encrypted file comes from, as recorded in its header.
+#[derive(Clone, Copy, PartialEq)]
+enum KeySource {
+    KeyFile = 0,
+    Passphrase = 1,
+}
+
+/// A key source and the salt a key was derived with.
+type KeyId = (u8, [u8; SALT_LEN]);
+
+/// The encryption settings of a run together with what was worked out from
+/// them so far, so that the passphrase is asked for and stretched only once.
+pub struct Keyring<'a> {
+    settings: &'a EncryptionConfig,
+    passphrase: RefCell<Option<String>>,
+    /// Derived keys by source and salt; Argon2 is deliberately slow.
+    keys: RefCell<HashMap<KeyId, [u8; 32]>>,
+    /// The salt whose passphrase key has been checked.
+    checked_salt: Cell<Option<[u8; SALT_LEN]>>,
+}
+
+/// A cache file that can't be decrypted with the key at hand, as opposed to
+/// one that is damaged in some other way.
+#[derive(Debug)]
+pub struct DecryptError(String);
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Whether `error` means a file couldn't be decrypted rather than that it is damaged.
+pub fn is_decrypt_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.is::<DecryptError>())
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+impl<'a> Keyring<'a> {
+    pub fn new(settings: &'a EncryptionConfig) -> Self {
+        Keyring {
+            settings,
+            passphrase: RefCell::new(None),
+            keys: RefCell::new(HashMap::new()),
+            checked_salt: Cell::new(None),
+        }
+    }
+
+    /// Whether new cache files should be encrypted.
+    pub fn enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    /// Encrypts `plaintext` with the configured key. `sessions_dir` holds the
+    /// salt that passphrase keys are derived with.
+    pub fn encrypt(&self, sessions_dir: &Path, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let (source, salt) = self.write_key_source(sessions_dir)?;
+        let cipher = XChaCha20Poly1305::new(&Key::from(self.key_for(source, &salt)?));
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt cache file"))?;
+
+        let mut output = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        output.extend_from_slice(MAGIC);
+        output.push(FORMAT_VERSION);
+        output.push(source as u8);
+        output.extend_from_slice(&salt);
+        output.extend_from_slice(&nonce);
+        output.extend_from_slice(&ciphertext);
+        Ok(output)
+    }
+
+    /// Decrypts a file written by `encrypt`, asking for the passphrase if needed.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < HEADER_LEN || !is_encrypted(data) {
+            return Err(anyhow::anyhow!("Not an encrypted cache file"));
+        }
+        if data[4] != FORMAT_VERSION {
+            return Err(anyhow::anyhow!("Unsupported cache encryption version {}", data[4]));
+        }
+        let source = match data[5] {
+            0 => KeySource::KeyFile,
+            1 => KeySource::Passphrase,
+            other => return Err(anyhow::anyhow!("Unknown cache key type {}", other)),
+        };
+        let salt: [u8; SALT_LEN] = data[6..6 + SALT_LEN].try_into()?;
+        let nonce = XNonce::from_slice(&data[6 + SALT_LEN..HEADER_LEN]);
+
+        let key = self.key_for(source, &salt).map_err(|e| DecryptError(format!("{:#}", e)))?;
+        open(&key, nonce, &data[HEADER_LEN..]).ok_or_else(|| {
+            let what = match source {
+                KeySource::KeyFile => "key file",
+                KeySource::Passphrase => "passphrase",
+            };
+            DecryptError(format!("Failed to decrypt cache file: wrong {} or corrupted data", what)).into()
+        })
+    }
+
+    /// A SHA-256 that can't be recomputed without the key, so that names of
+    /// encrypted blobs don't reveal which contents they hold.
+    pub fn keyed_hash(&self, sessions_dir: &Path, data: &[u8]) -> Result<String> {
+        let (source, salt) = self.write_key_source(sessions_dir)?;
+        let key = self.key_for(source, &salt)?;
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        hasher.update(data);
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// The key source and salt used for files written now.
+    fn write_key_source(&self, sessions_dir: &Path) -> Result<(KeySource, [u8; SALT_LEN])> {
+        if self.settings.key_file.is_some() {
+            return Ok((KeySource::KeyFile, [0; SALT_LEN]));
+        }
+        let (salt, created) = cache_salt(sessions_dir)?;
+        self.check_passphrase(sessions_dir, &salt, created)?;
+        Ok((KeySource::Passphrase, salt))
+    }
+
+    /// Makes sure the passphrase is the one the cache is encrypted with before
+    /// anything is written with it, asking for it twice when the cache is new.
+    fn check_passphrase(&self, sessions_dir: &Path, salt: &[u8; SALT_LEN], created: bool) -> Result<()> {
+        if self.checked_salt.get().as_ref() == Some(salt) {
+            return Ok(());
+        }
+        if created {
+            self.passphrase(true)?;
+        }
+        let key = self.key_for(KeySource::Passphrase, salt)?;
+
+        let check_path = sessions_dir.join(KEY_CHECK_FILE_NAME);
+        let matches = match fs::read(&check_path) {
+            Ok(check) => {
+                check.len() > NONCE_LEN
+                    && open(&key, XNonce::from_slice(&check[..NONCE_LEN]), &check[NONCE_LEN..]).as_deref()
+                        == Some(KEY_CHECK)
+            }
+            // Caches from before the check was added are compared with one of their files instead
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let matches = created || opens_existing_file(sessions_dir, salt, &key);
+                if matches {
+                    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                    let ciphertext = XChaCha20Poly1305::new(&Key::from(key))
+                        .encrypt(&nonce, KEY_CHECK)
+                        .map_err(|_| anyhow::anyhow!("Failed to encrypt the cache key check"))?;
+                    write_private(&check_path, &[nonce.as_slice(), &ciphertext].concat())
+                        .context("Failed to write the cache key check")?;
+                }
+                matches
+            }
+            Err(e) => return Err(e).context("Failed to read the cache key check"),
+        };
+        if !matches {
+            return Err(anyhow::anyhow!(
+                "The passphrase doesn't match the one this cache is encrypted with"
+            ));
+        }
+
+        self.checked_salt.set(Some(*salt));
+        Ok(())
+    }
+
+    fn key_for(&self, source: KeySource, salt: &[u8; SALT_LEN]) -> Result<[u8; 32]> {
+        if let Some(key) = self.keys.borrow().get(&(source as u8, *salt)) {
+            return Ok(*key);
+        }
+
+        let key = match source {
+            KeySource::KeyFile => {
+                let path = self.key_file_path().context(
+                    "This cache file is encrypted with a key file; set `key_file` under [cache.encryption] in the config",
+                )?;
+                let contents = fs::read(&path).with_context(|| format!("Failed to read key file {}", path.display()))?;
+                let contents = contents.trim_ascii();
+                if contents.is_empty() {
+                    return Err(anyhow::anyhow!("Key file {} is empty", path.display()));
+                }
+                Sha256::digest(contents).into()
+            }
+            KeySource::Passphrase => {
+                let passphrase = self.passphrase(false)?;
+                let mut key = [0u8; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| anyhow::anyhow!("Failed to derive cache key: {}", e))?;
+                key
+            }
+        };
+
+        self.keys.borrow_mut().insert((source as u8, *salt), key);
+        Ok(key)
+    }
+
+    fn key_file_path(&self) -> Option<PathBuf> {
+        self.settings.key_file.as_deref().map(expand_home)
+    }
+
+    /// Reads the passphrase from `XHINOBI_CACHE_PASSPHRASE`, or asks for it once
+    /// per run; a new passphrase (`confirm`) has to be typed twice.
+    fn passphrase(&self, confirm: bool) -> Result<String> {
+        if let Some(passphrase) = self.passphrase.borrow().as_ref() {
+            return Ok(passphrase.clone());
+        }
+
+        let passphrase = match env::var(PASSPHRASE_ENV) {
+            Ok(passphrase) if !passphrase.is_empty() => passphrase,
+            _ if std::io::stderr().is_terminal() && confirm => inquire::Password::new("New cache passphrase:")
+                .with_custom_confirmation_message("Confirm the passphrase:")
+                .with_custom_confirmation_error_message("The passphrases don't match")
+                .prompt()
+                .context("No cache passphrase given")?,
+            _ if std::io::stderr().is_terminal() => inquire::Password::new("Cache passphrase:")
+                .without_confirmation()
+                .prompt()
+                .context("No cache passphrase given")?,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "The cache is encrypted with a passphrase; set {} to use it without a terminal",
+                    PASSPHRASE_ENV
+                ))
+            }
+        };
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("The cache passphrase can't be empty"));
+        }
+
+        *self.passphrase.borrow_mut() = Some(passphrase.clone());
+        Ok(passphrase)
+    }
+}
+
+fn open(key: &[u8; 32], nonce: &XNonce, ciphertext: &[u8]) -> Option<Vec<u8>> {
+    XChaCha20Poly1305::new(&Key::from(*key)).decrypt(nonce, ciphertext).ok()
+}
+
+/// Writes a new random key file, readable only by the owner.
+pub fn generate_key_file(path: &Path) -> Result<()> {
+    let mut key = [0u8; KEY_FILE_BYTES];
+    OsRng.fill_bytes(&mut key);
+    let encoded: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    writeln!(file, "{}", encoded).context("Failed to write key file")?;
+
+    println!("Key file written to {}", path.display());
+    Ok(())
+}
+
+/// Reads the salt shared by the files of one cache, creating it on first use
+/// so that the passphrase only has to be stretched once per run. Also returns
+/// whether the salt is new.
+fn cache_salt(sessions_dir: &Path) -> Result<([u8; SALT_LEN], bool)> {
+    let salt_path = sessions_dir.join(SALT_FILE_NAME);
+    if let Ok(existing) = fs::read(&salt_path) {
+        if let Ok(salt) = existing.as_slice().try_into() {
+            return Ok((salt, false));
+        }
+    }
+
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    write_private(&salt_path, &salt).context("Failed to write cache encryption salt")?;
+    Ok((salt, true))
+}
+
+/// Whether `key` decrypts a session encrypted with the passphrase and `salt`,
+/// or there is no such session to compare with.
+fn opens_existing_file(sessions_dir: &Path, salt: &[u8; SALT_LEN], key: &[u8; 32]) -> bool {
+    let Ok(entries) = fs::read_dir(sessions_dir) else {
+        return true;
+    };
+    let existing = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".cache"))
+        .filter_map(|entry| fs::read(entry.path()).ok())
+        .find(|data| {
+            data.len() > HEADER_LEN
+                && is_encrypted(data)
+                && data[5] == KeySource::Passphrase as u8
+                && &data[6..6 + SALT_LEN] == salt
+        });
+    existing.is_none_or(|data| {
+        open(key, XNonce::from_slice(&data[6 + SALT_LEN..HEADER_LEN]), &data[HEADER_LEN..]).is_some()
+    })
+}
+
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+}
@@ -7,6 +7,7 @@ use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use sha2::{Digest, Sha256};
 use tiktoken_rs::o200k_base;
 
 pub fn is_text_file(filename: &str) -> bool {
@@ -399,6 +400,11 @@ pub fn copy_to_clipboard_osc52(text: &str) {
     print!("\x1b]52;c;{}\x07", encoded);
 }
 
+/// Returns the hex-encoded SHA-256 of `data`.
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
 /// Estimates the number of tokens based on the rule of thumb that 1 token is ~4 characters.
 pub fn estimate_tokens(text: &str) -> usize {
     ((text.len() as f64 / 4.0) * 1.3).ceil() as usize
@@ -497,20 +503,18 @@ pub fn write_output_file(path: &Path, content: &str) -> Result<(), Box<dyn std::
 pub fn copy_to_clipboard(text: &str) -> Result<String, Box<dyn std::error::Error>> {
     let is_wayland = env::var("WAYLAND_DISPLAY").is_ok();
 
-    if is_wayland {
-        if Command::new("wl-copy").arg("--version").output().is_ok() {
-            let mut child = Command::new("wl-copy").stdin(Stdio::piped()).spawn()?;
-            if let Some(mut stdin) = child.stdin.take() {
-                stdin.write_all(text.as_bytes())?;
-            } else {
-                return Err("Failed to open stdin for the wl-copy process.".into());
-            }
-            let status = child.wait()?;
-            if status.success() {
-                return Ok("wl-copy".to_string());
-            } else {
-                return Err(format!("wl-copy process exited with status: {}", status).into());
-            }
+    if is_wayland && Command::new("wl-copy").arg("--version").output().is_ok() {
+        let mut child = Command::new("wl-copy").stdin(Stdio::piped()).spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        } else {
+            return Err("Failed to open stdin for the wl-copy process.".into());
+        }
+        let status = child.wait()?;
+        if status.success() {
+            return Ok("wl-copy".to_string());
+        } else {
+            return Err(format!("wl-copy process exited with status: {}", status).into());
         }
     }
 
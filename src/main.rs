@@ -1,28 +1,40 @@
-use clap::Parser;
-use std::io::{self, BufRead, BufReader};
+use clap::{Parser, ValueEnum};
+use std::io::{self, BufRead, BufReader, IsTerminal};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::env;
-use regex::Regex;
 
 mod cli;
+mod config;
 mod constants;
 mod helpers;
 mod decomment;
+mod embedded;
+mod minify;
 mod cache;
+mod encryption;
 
-use cli::Args;
+use cli::{Args, CacheCommand, CacheDiffArgs, CacheLsArgs, CacheMode, CacheRerunArgs, Command};
+use config::Config;
+use encryption::Keyring;
 use constants::*;
 use helpers::*;
 use colored::Colorize;
+use chrono::{DateTime, Utc};
+use anyhow::Context;
 
 #[derive(Debug)]
 struct FileData {
     text: String,
     name: String,
+    /// Path as given on stdin, relative to the working directory where possible.
+    path: String,
+    size: u64,
+    hash: Option<String>,
+    modified: Option<DateTime<Utc>>,
 }
 
-fn get_files(files: &[String], args: &Args) -> Vec<FileData> {
+fn get_files(files: &[String], args: &Args, options: &decomment::DecommentOptions) -> Vec<FileData> {
     let mut results = Vec::new();
     
     'outer: for file in files {
@@ -46,9 +58,15 @@ fn get_files(files: &[String], args: &Args) -> Vec<FileData> {
             .unwrap_or(file)
             .to_string();
         
+        let metadata = fs::metadata(&file_path).ok();
+        let mut hash = None;
+
         let mut file_content = if is_text_file(&basename) {
             match fs::read_to_string(&file_path) {
-                Ok(content) => content,
+                Ok(content) => {
+                    hash = Some(content_hash(content.as_bytes()));
+                    content
+                }
                 Err(e) => {
                     eprintln!("Error reading file {}: {}", file, e);
                     continue;
@@ -60,45 +78,71 @@ fn get_files(files: &[String], args: &Args) -> Vec<FileData> {
 
         if args.decomment {
             if let Some(language) = decomment::get_language(&file_path) {
-                match decomment::clean_code(&file_content, language) {
+                match decomment::clean_code(&file_content, language, options) {
+                    Ok(cleaned_content) => file_content = cleaned_content,
+                    Err(e) => eprintln!("Warning: Failed to decomment {}: {}", file, e),
+                }
+            } else if let Some(host) = embedded::get_host_language(&file_path) {
+                match decomment::clean_embedded(&file_content, host, options) {
                     Ok(cleaned_content) => file_content = cleaned_content,
                     Err(e) => eprintln!("Warning: Failed to decomment {}: {}", file, e),
                 }
             }
         }
         
+        // Minify each file on its own so headers and the tree keep their layout
+        if args.minify {
+            file_content = minify::minify(&file_content, &file_path);
+        }
+
+        let relative_path = file_path
+            .strip_prefix(&current_dir)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| file.trim_start_matches("./").to_string());
+
         results.push(FileData {
             text: file_content,
             name: format!("<{}>", basename),
+            path: relative_path,
+            size: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+            hash,
+            modified: metadata.and_then(|m| m.modified().ok()).map(DateTime::<Utc>::from),
         });
     }
     
     results
 }
 
-fn process_files(files: &[FileData], args: &Args) -> String {
+fn process_files(files: &[FileData], args: &Args) -> (String, Vec<cache::ManifestFile>) {
     let mut final_output = String::new();
+    let mut manifest = Vec::new();
 
     // Add tree if requested
     if args.tree {
         final_output.push_str(&get_tree_output(&args.ignore));
     }
 
-    // Process each file
+    // Process each file; minified files start on their own line
     for file_data in files {
+        if args.minify && !final_output.is_empty() && !final_output.ends_with('\n') {
+            final_output.push('\n');
+        }
         if args.prepend_file_name {
             final_output.push_str(&format!("{} ", file_data.name));
         }
+        manifest.push(cache::ManifestFile {
+            path: file_data.path.clone(),
+            size: file_data.size,
+            hash: file_data.hash.clone(),
+            modified: file_data.modified,
+            offset: final_output.len(),
+            length: file_data.text.len(),
+            blob: None,
+        });
         final_output.push_str(&file_data.text);
     }
 
-    // Minify if requested
-    if args.minify {
-        let re = Regex::new(r"\s+").unwrap();
-        final_output = re.replace_all(&final_output, " ").trim().to_string();
-    }
-
-    final_output
+    (final_output, manifest)
 }
 
 fn output_to_clipboard(content: &str, args: &Args, token_display: &str) {
@@ -136,15 +180,316 @@ fn output_to_clipboard(content: &str, args: &Args, token_display: &str) {
     }
 }
 
+fn decomment_options(args: &Args, config: &Config) -> decomment::DecommentOptions {
+    let keep_rules = match decomment::KeepRules::new(&config.decomment) {
+        Ok(rules) => rules,
+        Err(e) => {
+            eprintln!("Error loading config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    decomment::DecommentOptions {
+        keep: keep_rules,
+        blank_lines: args.blank_lines.or(config.decomment.blank_lines),
+        include_fences: args.decomment_fences,
+    }
+}
+
+/// Loads the cache retention settings, letting command-line flags override the config file.
+fn cache_config(args: &Args, config: &Config) -> config::CacheConfig {
+    let mut cache_config = config.cache.clone();
+    if let Some(max_entries) = args.max_cache_entries {
+        cache_config.max_entries = max_entries;
+    }
+    if let Some(max_size_mb) = args.max_cache_size_mb {
+        cache_config.max_size_mb = max_size_mb;
+    }
+    if let Some(max_age_days) = args.max_cache_age_days {
+        cache_config.max_age_days = max_age_days;
+    }
+    cache_config
+}
+
+fn aggregate(file_paths: &[String], args: &Args, config: &Config, keyring: &Keyring) {
+    let decomment_options = decomment_options(args, config);
+
+    let content = get_files(file_paths, args, &decomment_options);
+    let (final_output, manifest) = process_files(&content, args);
+    let token_count = count_tokens(
+        &final_output,
+        &args.token_counter,
+        args.gemini_multiplier,
+    );
+    let token_display = token_count_display(token_count, &args.token_counter);
+    let token_display_colored = token_display.cyan().to_string();
+
+    if let Some(output_path) = &args.output_file {
+        let path = PathBuf::from(output_path);
+        match write_output_file(&path, &final_output) {
+            Ok(_) => {
+                println!(
+                    "Wrote {} characters ({}) to {}",
+                    final_output.len(),
+                    token_display_colored,
+                    path.display()
+                );
+            }
+            Err(e) => {
+                eprintln!("Failed to write output file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Always output to clipboard after write (or when no file requested)
+    output_to_clipboard(&final_output, args, &token_display_colored);
+
+    // Save to cache (auto-save unless turned off)
+    let cache_config = cache_config(args, config);
+    let cache_mode = cache_mode(args, &cache_config);
+    if cache_mode == CacheMode::Off {
+        return;
+    }
+    if let Err(e) = cache::save_to_cache(
+        &final_output,
+        manifest,
+        cache::SessionOptions::from_args(args),
+        &args.cache_dir,
+        token_count,
+        cache_mode == CacheMode::Metadata,
+        &cache_config,
+        keyring,
+    ) {
+        eprintln!("Warning: Failed to save to cache: {}", e);
+    }
+}
+
+/// Decides what this run saves to the cache: `--no-cache`, then `--cache-mode`
+/// or `XHINOBI_CACHE_MODE`, then the config for the current directory.
+fn cache_mode(args: &Args, cache_config: &config::CacheConfig) -> CacheMode {
+    if args.no_cache {
+        return CacheMode::Off;
+    }
+    if let Some(mode) = args.cache_mode {
+        return mode;
+    }
+    let working_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+    cache_config.mode_for(&working_dir.to_string_lossy())
+}
+
+/// Enters a cached session's working directory and rebuilds the options it was created with.
+fn restore_session(
+    args: &Args,
+    entry: &cache::CacheEntry,
+    output_file: &Option<String>,
+    osc52: bool,
+) -> anyhow::Result<Args> {
+    // Paths given on the command line are relative to where the user ran us
+    let current_dir = env::current_dir().context("Failed to read the current directory")?;
+    let absolute = |path: &Option<String>| path.as_ref().map(|p| current_dir.join(p).to_string_lossy().to_string());
+    let (cache_dir, config, output_file) = (absolute(&args.cache_dir), absolute(&args.config), absolute(output_file));
+
+    env::set_current_dir(&entry.working_dir)
+        .with_context(|| format!("Failed to enter working directory {}", entry.working_dir))?;
+
+    let mut cli = vec!["xhinobi".to_string()];
+    cli.extend(entry.options.to_flags());
+    if osc52 {
+        cli.push("--osc52".to_string());
+    }
+    if args.no_cache {
+        cli.push("--no-cache".to_string());
+    }
+    if let Some(value) = args.cache_mode.and_then(|mode| mode.to_possible_value()) {
+        cli.push("--cache-mode".to_string());
+        cli.push(value.get_name().to_string());
+    }
+    for (flag, value) in [
+        ("--cache-dir", cache_dir),
+        ("--config", config),
+        ("--output-file", output_file),
+        ("--max-cache-entries", args.max_cache_entries.map(|n| n.to_string())),
+        ("--max-cache-size-mb", args.max_cache_size_mb.map(|n| n.to_string())),
+        ("--max-cache-age-days", args.max_cache_age_days.map(|n| n.to_string())),
+    ] {
+        if let Some(value) = value {
+            cli.push(flag.to_string());
+            cli.push(value);
+        }
+    }
+
+    Args::try_parse_from(cli).context("Failed to restore the session's options")
+}
+
+/// Compares a cached session with its files as they are on disk now, processed with the same options.
+fn diff_cache_entry_live(
+    args: &Args,
+    config: &Config,
+    diff_args: &CacheDiffArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> anyhow::Result<()> {
+    let (filename, entry) = cache::find_cache_entry(&args.cache_dir, &diff_args.old, project_root, keyring)?;
+    let cached = cache::entry_snapshot(&filename, &entry)?;
+    let stored_args = restore_session(args, &entry, &None, false)?;
+
+    let file_paths: Vec<String> = cached.keys().filter(|path| Path::new(path).exists()).cloned().collect();
+    let decomment_options = decomment_options(&stored_args, config);
+    let live = get_files(&file_paths, &stored_args, &decomment_options)
+        .into_iter()
+        .map(|file| (file.path, cache::FileSnapshot { hash: file.hash, text: file.text }))
+        .collect();
+
+    cache::print_snapshot_diff(&filename, &cached, "working tree", &live, diff_args.stat);
+
+    Ok(())
+}
+
+/// Aggregates the files of a cached session again, as they are on disk now.
+fn rerun_cache_entry(
+    args: &Args,
+    config: &Config,
+    rerun_args: &CacheRerunArgs,
+    project_root: Option<&str>,
+    keyring: &Keyring,
+) -> anyhow::Result<()> {
+    let (filename, entry) = cache::find_cache_entry(&args.cache_dir, &rerun_args.id, project_root, keyring)?;
+    if entry.files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Cache entry {} has no file manifest (it was created by an older version) and cannot be re-run",
+            filename
+        ));
+    }
+
+    let stored_args = restore_session(args, &entry, &rerun_args.output_file, args.osc52 || rerun_args.osc52)?;
+
+    let mut file_paths = Vec::new();
+    let (mut changed, mut missing) = (0, 0);
+    for file in &entry.files {
+        let path = Path::new(&file.path);
+        if !path.exists() {
+            println!("{} {}", "Missing:".yellow(), file.path);
+            missing += 1;
+            continue;
+        }
+        if let Some(hash) = &file.hash {
+            if fs::read(path).map(|data| content_hash(&data)).ok().as_ref() != Some(hash) {
+                changed += 1;
+            }
+        }
+        file_paths.push(file.path.clone());
+    }
+
+    println!(
+        "Re-running {} from {}: {} files, {} changed, {} missing",
+        filename,
+        entry.working_dir,
+        entry.files.len(),
+        changed,
+        missing
+    );
+
+    if file_paths.is_empty() {
+        return Err(anyhow::anyhow!("None of the session's files exist anymore"));
+    }
+    aggregate(&file_paths, &stored_args, config, keyring);
+
+    Ok(())
+}
+
 fn main() {
     let args = Args::parse();
+    // A rerun keeps --config, so its session uses this same config
+    let config = match config::load_config(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading config: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let keyring = Keyring::new(&config.cache.encryption);
+
+    // Session numbers count the current project's sessions unless --all-projects is given
+    let project_root = (!args.all_projects).then(cache::current_project_root);
+    let project_root = project_root.as_deref();
+
+    if let Some(Command::Cache { action }) = &args.command {
+        let result = match action {
+            CacheCommand::Ls(ls_args) => cache::print_cache_list(&args.cache_dir, ls_args, project_root, &keyring),
+            CacheCommand::Search(search_args) => {
+                cache::search_cache(&args.cache_dir, search_args, project_root, &keyring)
+            }
+            CacheCommand::Show(show_args) => {
+                let osc52 = args.osc52 || show_args.osc52;
+                cache::show_cache_entry(&args.cache_dir, show_args, project_root, osc52, &keyring)
+            }
+            CacheCommand::Rerun(rerun_args) => rerun_cache_entry(&args, &config, rerun_args, project_root, &keyring),
+            CacheCommand::Diff(diff_args) if diff_args.live => {
+                diff_cache_entry_live(&args, &config, diff_args, project_root, &keyring)
+            }
+            CacheCommand::Diff(diff_args) => {
+                cache::diff_cache_entries(&args.cache_dir, diff_args, project_root, &keyring)
+            }
+            CacheCommand::Rm(rm_args) => {
+                cache::remove_cache_entries(&args.cache_dir, rm_args, project_root, &keyring)
+            }
+            CacheCommand::Merge(merge_args) => cache::merge_cache_entries(
+                &args.cache_dir,
+                merge_args,
+                project_root,
+                &cache_config(&args, &config),
+                args.osc52 || merge_args.osc52,
+                &keyring,
+            ),
+            CacheCommand::Stats(stats_args) => {
+                cache::print_cache_stats(&args.cache_dir, stats_args, project_root, &keyring)
+            }
+            CacheCommand::Prune(prune_args) => {
+                cache::prune_cache(&args.cache_dir, &cache_config(&args, &config), prune_args, &keyring)
+            }
+            CacheCommand::Name(name_args) => {
+                cache::name_cache_entry(&args.cache_dir, name_args, project_root, &keyring)
+            }
+            CacheCommand::Tag(tag_args) => cache::tag_cache_entry(&args.cache_dir, tag_args, project_root, &keyring),
+            CacheCommand::Pin(pin_args) => {
+                cache::pin_cache_entry(&args.cache_dir, &pin_args.id, project_root, true, &keyring)
+            }
+            CacheCommand::Unpin(pin_args) => {
+                cache::pin_cache_entry(&args.cache_dir, &pin_args.id, project_root, false, &keyring)
+            }
+            CacheCommand::Doctor(doctor_args) => cache::doctor_cache(&args.cache_dir, doctor_args, &keyring),
+            CacheCommand::Export(export_args) => {
+                cache::export_cache_entries(&args.cache_dir, export_args, project_root, &keyring)
+            }
+            CacheCommand::Import(import_args) => {
+                cache::import_cache_bundle(&args.cache_dir, import_args, &cache_config(&args, &config), &keyring)
+            }
+            CacheCommand::Keygen(keygen_args) => {
+                encryption::generate_key_file(Path::new(&keygen_args.path))
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     // Handle cache-only operations
-    if args.cache {
-        match cache::load_most_recent_cache(&args.cache_dir) {
-            Ok(entry) => {
-                cache::copy_cache_to_clipboard(&entry, args.osc52).unwrap();
+    if let Some(selector) = &args.cache {
+        let loaded = match selector {
+            Some(selector) => {
+                cache::find_cache_entry(&args.cache_dir, selector, project_root, &keyring).map(|(_, entry)| entry)
             }
+            None => cache::load_most_recent_cache(&args.cache_dir, project_root, &keyring),
+        };
+        let delivered = loaded.and_then(|entry| match &args.output_file {
+            Some(path) => cache::write_cache_to_file(&entry, Path::new(path)),
+            None if args.stdout => cache::print_cache_content(&entry),
+            None => cache::copy_cache_to_clipboard(&entry, args.osc52),
+        });
+        match delivered {
+            Ok(()) => {}
             Err(e) => {
                 eprintln!("Error loading cache: {}", e);
                 std::process::exit(1);
@@ -154,7 +499,13 @@ fn main() {
     }
 
     if args.list_cache {
-        match cache::interactive_cache_selection(&args.cache_dir, args.osc52) {
+        // Prompts need a terminal; print the list instead for pipes and scripts
+        let result = if io::stdin().is_terminal() && io::stdout().is_terminal() {
+            cache::interactive_cache_selection(&args.cache_dir, project_root, args.osc52, &keyring)
+        } else {
+            cache::print_cache_list(&args.cache_dir, &CacheLsArgs { json: false }, project_root, &keyring)
+        };
+        match result {
             Ok(_) => {}
             Err(e) => {
                 eprintln!("Error with cache selection: {}", e);
@@ -199,60 +550,6 @@ fn main() {
         .collect();
 
     if !file_paths.is_empty() {
-        let content = get_files(&file_paths, &args);
-        let final_output = process_files(&content, &args);
-        let token_count = count_tokens(
-            &final_output,
-            &args.token_counter,
-            args.gemini_multiplier,
-        );
-        let token_display = token_count_display(token_count, &args.token_counter);
-        let token_display_colored = token_display.cyan().to_string();
-
-        if let Some(output_path) = &args.output_file {
-            let path = PathBuf::from(output_path);
-            match write_output_file(&path, &final_output) {
-                Ok(_) => {
-                    println!(
-                        "Wrote {} characters ({}) to {}",
-                        final_output.len(),
-                        token_display_colored,
-                        path.display()
-                    );
-                }
-                Err(e) => {
-                    eprintln!("Failed to write output file: {}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
-
-        // Always output to clipboard after write (or when no file requested)
-        output_to_clipboard(&final_output, &args, &token_display_colored);
-
-        // Save to cache (auto-save by default)
-        let args_string = format!(
-            "tree={} decomment={} minify={} prepend={} osc52={} ignore={} token_counter={} gemini_multiplier={} output_file={}",
-            args.tree,
-            args.decomment,
-            args.minify,
-            args.prepend_file_name,
-            args.osc52,
-            args.ignore.join(","),
-            token_counter_id(&args.token_counter),
-            args.gemini_multiplier,
-            args.output_file.clone().unwrap_or_else(|| "none".to_string())
-        );
-
-        if let Err(e) = cache::save_to_cache(
-            &final_output,
-            content.len(),
-            &args_string,
-            &args.cache_dir,
-            token_count,
-            Some(token_counter_id(&args.token_counter).to_string()),
-        ) {
-            eprintln!("Warning: Failed to save to cache: {}", e);
-        }
+        aggregate(&file_paths, &args, &config, &keyring);
     }
 }
@@ -0,0 +1,169 @@
+use std::path::Path;
+use tree_sitter::{Node, Parser};
+
+use crate::decomment::{self, Grammar};
+use crate::embedded::{self, HostLanguage};
+
+/// How much whitespace a language lets us remove.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Newlines and indentation carry no meaning (JSON, Rust, Dart).
+    Compact,
+    /// Newlines may end statements (JavaScript, TypeScript, Go, Lua, PHP).
+    KeepNewlines,
+    /// Newlines end commands; words must stay apart (Bash).
+    Shell,
+    /// Indentation is syntax (Python).
+    KeepIndentation,
+}
+
+fn mode_for(name: &str) -> Mode {
+    match name {
+        "json" | "rust" | "dart" => Mode::Compact,
+        "python" => Mode::KeepIndentation,
+        "bash" => Mode::Shell,
+        _ => Mode::KeepNewlines,
+    }
+}
+
+/// Minifies a file's content as far as its language safely allows.
+///
+/// Files with a tree-sitter grammar are re-emitted token by token, keeping the
+/// text of strings, comments and regex literals intact. Everything else, and
+/// any file that fails to parse cleanly, is only tidied line by line so that
+/// indentation-sensitive formats such as YAML and Makefiles survive.
+pub fn minify(content: &str, file_path: &Path) -> String {
+    if let Some(grammar) = decomment::get_language(file_path) {
+        if let Some(minified) = minify_code(content, &grammar) {
+            return minified;
+        }
+    }
+
+    // Trailing spaces are hard line breaks in Markdown
+    let trim_trailing = embedded::get_host_language(file_path) != Some(HostLanguage::Markdown);
+    minify_lines(content, trim_trailing)
+}
+
+fn minify_code(content: &str, grammar: &Grammar) -> Option<String> {
+    let mut parser = Parser::new();
+    parser.set_language(&grammar.language).ok()?;
+    let tree = parser.parse(content, None)?;
+    if tree.root_node().has_error() {
+        return None;
+    }
+
+    let mode = mode_for(grammar.name);
+    let mut tokens = Vec::new();
+    collect_tokens(tree.root_node(), &mut tokens);
+
+    let mut output = String::with_capacity(content.len());
+    let mut previous: Option<Node> = None;
+
+    for token in tokens {
+        let text = &content[token.start_byte()..token.end_byte()];
+        if let Some(prev) = previous {
+            // Already emitted as part of an enclosing literal
+            if token.start_byte() < prev.end_byte() {
+                continue;
+            }
+            let gap = &content[prev.end_byte()..token.start_byte()];
+            let prev_text = &content[prev.start_byte()..prev.end_byte()];
+            output.push_str(&separator(gap, prev_text, text, prev.kind().contains("comment"), mode));
+        } else if mode == Mode::KeepIndentation {
+            output.push_str(content[..token.start_byte()].rsplit('\n').next().unwrap_or(""));
+        }
+        output.push_str(text);
+        previous = Some(token);
+    }
+
+    Some(output)
+}
+
+/// Collects leaf tokens, treating literals whose inner whitespace matters as a single token.
+fn collect_tokens<'a>(node: Node<'a>, tokens: &mut Vec<Node<'a>>) {
+    let kind = node.kind();
+    let atomic = kind.contains("string")
+        || kind.contains("char")
+        || kind.contains("comment")
+        || kind.contains("regex")
+        || kind.contains("heredoc")
+        || kind.contains("nowdoc");
+
+    if node.child_count() == 0 || atomic {
+        if node.end_byte() > node.start_byte() {
+            tokens.push(node);
+        }
+        return;
+    }
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        collect_tokens(child, tokens);
+    }
+}
+
+/// Chooses what to put between two tokens in place of the original `gap`.
+fn separator(gap: &str, prev: &str, next: &str, after_comment: bool, mode: Mode) -> String {
+    if gap.is_empty() {
+        return String::new();
+    }
+    // Text the grammar does not expose as a token (e.g. line continuations) is kept as is.
+    if !gap.chars().all(char::is_whitespace) {
+        return gap.to_string();
+    }
+
+    if gap.contains('\n') {
+        match mode {
+            Mode::KeepIndentation => {
+                let indentation = gap.rsplit('\n').next().unwrap_or("");
+                return format!("\n{}", indentation);
+            }
+            Mode::KeepNewlines | Mode::Shell => return "\n".to_string(),
+            Mode::Compact if after_comment => return "\n".to_string(),
+            Mode::Compact => {}
+        }
+    }
+
+    if mode == Mode::Shell || needs_space(prev, next) {
+        " ".to_string()
+    } else {
+        String::new()
+    }
+}
+
+/// Whether joining two tokens without whitespace could merge them into one.
+fn needs_space(prev: &str, next: &str) -> bool {
+    let (Some(last), Some(first)) = (prev.chars().last(), next.chars().next()) else {
+        return false;
+    };
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$' || c == '"' || c == '\'' || !c.is_ascii();
+    let is_delimiter = |c: char| matches!(c, '(' | ')' | '[' | ']' | '{' | '}' | ',' | ';');
+
+    if is_delimiter(last) || is_delimiter(first) {
+        return false;
+    }
+    if last.is_ascii_digit() && first == '.' {
+        return true;
+    }
+    is_word(last) == is_word(first)
+}
+
+/// Collapses runs of blank lines and trims trailing whitespace, leaving
+/// indentation and single blank lines (which may be content, as in YAML block
+/// scalars) untouched.
+fn minify_lines(content: &str, trim_trailing: bool) -> String {
+    let mut kept: Vec<&str> = Vec::new();
+    let mut previous_blank = true;
+
+    for line in content.lines() {
+        let blank = line.trim().is_empty();
+        if blank && previous_blank {
+            continue;
+        }
+        kept.push(if trim_trailing || blank { line.trim_end() } else { line });
+        previous_blank = blank;
+    }
+
+    kept.join("\n").trim_end().to_string()
+}
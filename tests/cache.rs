@@ -0,0 +1,100 @@
+mod common;
+
+use common::xhinobi;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o777
+}
+
+/// Lays out a cache the way versions before private permissions left it.
+fn old_cache(cache_dir: &Path) {
+    let sessions_dir = cache_dir.join("sessions");
+    fs::create_dir_all(&sessions_dir).unwrap();
+    fs::write(sessions_dir.join("cache_index.json"), r#"{"entries":[]}"#).unwrap();
+    for path in [cache_dir, &sessions_dir] {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    fs::set_permissions(sessions_dir.join("cache_index.json"), fs::Permissions::from_mode(0o644)).unwrap();
+}
+
+#[test]
+fn opening_an_old_cache_makes_it_private() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // The default cache directory belongs to xhinobi
+    let default_dir = dir.path().join("xdg").join("xhinobi");
+    old_cache(&default_dir);
+    let xdg = dir.path().join("xdg");
+    let run = common::xhinobi_with_env(dir.path(), &["cache", "ls"], "", &[("XDG_CACHE_HOME", xdg.to_str().unwrap())]);
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(mode(&default_dir), 0o700);
+
+    // A directory given with --cache-dir may be shared, so only xhinobi's own files change
+    let shared_dir = dir.path().join("shared");
+    old_cache(&shared_dir);
+    let run = xhinobi(dir.path(), &["--cache-dir", shared_dir.to_str().unwrap(), "cache", "ls"], "");
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(mode(&shared_dir), 0o755);
+
+    for cache_dir in [&default_dir, &shared_dir] {
+        assert_eq!(mode(&cache_dir.join("sessions")), 0o700);
+        assert_eq!(mode(&cache_dir.join("sessions").join("cache_index.json")), 0o600);
+    }
+}
+
+#[test]
+fn a_mismatched_passphrase_is_rejected_before_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "hello\n").unwrap();
+    fs::write(dir.path().join("cfg.toml"), "[cache.encryption]\nenabled = true\n").unwrap();
+    let sessions = |dir: &Path| {
+        fs::read_dir(dir.join("cache").join("sessions"))
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".cache"))
+            .count()
+    };
+    let run = |passphrase: &str| {
+        common::xhinobi_with_env(
+            dir.path(),
+            &["--config", "cfg.toml", "--cache-dir", "cache", "--osc52"],
+            "a.txt\n",
+            &[("XHINOBI_CACHE_PASSPHRASE", passphrase)],
+        )
+    };
+
+    assert!(run("right").status.success());
+    assert_eq!(sessions(dir.path()), 1);
+
+    let wrong = run("wrong");
+    assert!(String::from_utf8_lossy(&wrong.stderr).contains("passphrase doesn't match"));
+    assert_eq!(sessions(dir.path()), 1);
+}
+
+#[test]
+fn out_of_range_retention_limits_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("cfg.toml"), "[cache]\nmax_age_days = 100000000\n").unwrap();
+
+    let from_config = xhinobi(dir.path(), &["--config", "cfg.toml", "--cache-dir", "cache", "cache", "ls"], "");
+    assert!(String::from_utf8_lossy(&from_config.stderr).contains("max_age_days must be between 0 and 36500"));
+
+    for flag in ["--max-cache-age-days=100000000", "--max-cache-size-mb=18446744073709551615"] {
+        let from_flag = xhinobi(dir.path(), &["--cache-dir", "cache", flag, "cache", "ls"], "");
+        assert!(!from_flag.status.success());
+        assert!(String::from_utf8_lossy(&from_flag.stderr).contains("is not in"), "{}", flag);
+    }
+}
+
+#[test]
+fn huge_relative_dates_are_invalid() {
+    let dir = tempfile::tempdir().unwrap();
+    for args in [&["cache", "search", "--since", "99999999d"][..], &["cache", "rm", "--older-than", "9999999999999999h"]] {
+        let run = xhinobi(dir.path(), &[&["--cache-dir", "cache"][..], args].concat(), "");
+        assert!(!run.status.success());
+        assert!(String::from_utf8_lossy(&run.stderr).contains("Invalid date"), "{:?}", args);
+    }
+}
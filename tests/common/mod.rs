@@ -0,0 +1,27 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the binary in `dir` with `stdin` as its input and no user config.
+#[allow(dead_code)]
+pub fn xhinobi(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    xhinobi_with_env(dir, args, stdin, &[])
+}
+
+/// Like `xhinobi`, with extra environment variables.
+#[allow(dead_code)]
+pub fn xhinobi_with_env(dir: &Path, args: &[&str], stdin: &str, vars: &[(&str, &str)]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_xhinobi"))
+        .args(args)
+        .envs(vars.iter().copied())
+        .current_dir(dir)
+        .env("XDG_CONFIG_HOME", dir.join("no-config"))
+        .env_remove("XHINOBI_CACHE_MODE")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start xhinobi");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
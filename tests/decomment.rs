@@ -0,0 +1,63 @@
+mod common;
+
+use common::xhinobi;
+use std::fs;
+
+#[test]
+fn keep_rules_accept_language_aliases() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.py"), "# keep me\n# drop me\nx = 1\n").unwrap();
+    fs::write(dir.path().join("cfg.toml"), "[decomment.keep]\npy = [\"^# keep\"]\n").unwrap();
+
+    let run = xhinobi(
+        dir.path(),
+        &["--config", "cfg.toml", "--decomment", "--no-cache", "--output-file", "out.txt"],
+        "a.py\n",
+    );
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(fs::read_to_string(dir.path().join("out.txt")).unwrap(), "# keep me\nx = 1");
+}
+
+#[test]
+fn keep_rules_reject_unknown_languages() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.py"), "x = 1\n").unwrap();
+    fs::write(dir.path().join("cfg.toml"), "[decomment.keep]\npyhton = []\n").unwrap();
+
+    let run = xhinobi(dir.path(), &["--config", "cfg.toml", "--decomment", "--no-cache"], "a.py\n");
+    assert!(!run.status.success());
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    assert!(stderr.contains("Unknown language 'pyhton'"), "{}", stderr);
+    assert!(stderr.contains("python"), "{}", stderr);
+}
+
+#[test]
+fn fenced_code_loses_trailing_whitespace_with_its_comments() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.md"), "Hard break  \nnext\n\n```python\nx = 1  # drop\n```\n").unwrap();
+
+    let run = xhinobi(
+        dir.path(),
+        &["--decomment", "--decomment-fences", "--no-cache", "--output-file", "out.txt"],
+        "a.md\n",
+    );
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(
+        fs::read_to_string(dir.path().join("out.txt")).unwrap(),
+        "Hard break  \nnext\n\n```python\nx = 1\n```\n"
+    );
+}
+
+#[test]
+fn markdown_keeps_unclosed_comment_openers_and_code_spans() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = "Start a comment with `<!--` in HTML.\n\nImportant paragraph.\n\n## Next section\n\nInline `<!-- x -->` example\n";
+    fs::write(dir.path().join("a.md"), text).unwrap();
+    fs::write(dir.path().join("b.md"), "Text <!-- note --> here\n<!-- block\n\ncomment -->\nEnd\n").unwrap();
+
+    for (file, expected) in [("a.md", text), ("b.md", "Text  here\nEnd\n")] {
+        let run = xhinobi(dir.path(), &["--decomment", "--no-cache", "--output-file", "out.txt"], &format!("{}\n", file));
+        assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+        assert_eq!(fs::read_to_string(dir.path().join("out.txt")).unwrap(), expected);
+    }
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::xhinobi;
+use std::fs;
+
+#[test]
+fn rerun_resolves_relative_paths_against_the_callers_directory() {
+    let root = tempfile::tempdir().unwrap();
+    let session_dir = root.path().join("session");
+    let user_dir = root.path().join("user");
+    fs::create_dir_all(&session_dir).unwrap();
+    fs::create_dir_all(&user_dir).unwrap();
+    fs::write(session_dir.join("a.txt"), "hello\n").unwrap();
+    fs::write(user_dir.join("cfg.toml"), "").unwrap();
+
+    let cache_dir = user_dir.join("cache");
+    let run = xhinobi(&session_dir, &["--cache-dir", cache_dir.to_str().unwrap()], "a.txt\n");
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+
+    let session = fs::read_dir(cache_dir.join("sessions"))
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .find(|name| name.ends_with(".cache"))
+        .expect("session was not cached");
+
+    let rerun = xhinobi(
+        &user_dir,
+        &[
+            "--cache-dir",
+            "cache",
+            "--config",
+            "cfg.toml",
+            "cache",
+            "rerun",
+            &session,
+            "--output-file",
+            "out.txt",
+        ],
+        "",
+    );
+    assert!(rerun.status.success(), "{}", String::from_utf8_lossy(&rerun.stderr));
+
+    assert_eq!(fs::read_to_string(user_dir.join("out.txt")).unwrap(), "hello\n");
+    assert!(!session_dir.join("out.txt").exists());
+    assert!(!session_dir.join("cache").exists());
+}